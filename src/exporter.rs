@@ -1,75 +1,428 @@
 use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
-use graphql_client::reqwest::post_graphql;
-use graphql_client::GraphQLQuery;
+use graphql_client::{GraphQLQuery, PathFragment, Response};
 use prometheus_client::encoding::text::encode as prom_encode;
 use prometheus_client::registry::Registry;
 use reqwest::Client;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt;
 use std::rc::Rc;
+use std::time::Instant;
 
+mod cron_schedule;
+mod event_log;
 mod float_gauge;
+mod histogram;
 mod labels;
 mod metrics;
+mod options;
+mod seen_runs;
+mod state;
 mod update;
 
-use metrics::Metrics;
+use labels::{AssetLabel, CollectionErrorLabel, QueryLabel};
+use metrics::{now, Metrics};
+pub use options::Options;
+use state::State;
 
 pub struct Exporter {
+    name: String,
     url: String,
     metrics: RefCell<Metrics>,
     registry: Registry,
     client: Client,
-    refresh: Rc<RefCell<bool>>
+    refresh: Rc<RefCell<bool>>,
+    partition_refresh: Option<Rc<RefCell<bool>>>,
+    auto_materialize_refresh: Option<Rc<RefCell<bool>>>,
+    options: Options,
+    /// Held for the duration of a collection, so that concurrent scrapes never query Dagit with the same cursor
+    collecting: Mutex<()>,
+    /// How many collections have finished, and the error (if any) the latest of them failed with
+    collections: Cell<u64>,
+    last_error: RefCell<Option<String>>
 }
 
 impl Exporter {
-    pub fn new(url: String, refresh: Rc<RefCell<bool>>, concurrency_metrics: bool) -> Self {
-        let metrics = Metrics::new(concurrency_metrics);
+    pub fn new(
+        name: String, url: String, refresh: Rc<RefCell<bool>>, partition_refresh: Option<Rc<RefCell<bool>>>,
+        auto_materialize_refresh: Option<Rc<RefCell<bool>>>, options: &Options
+    ) -> Self {
+        let mut metrics = Metrics::new(&name, options);
         let registry = metrics.registry();
+
+        if let Some(path) = &options.state_file {
+            if let Err(e) = State::load(path).and_then(|state| state.map_or(Ok(()), |s| metrics.restore(s))) {
+                eprintln!("Ignoring the state in {}: {e}", path.display());
+            }
+        }
+
         Self {
+            name,
             url,
             metrics: RefCell::new(metrics),
             registry,
-            client: Client::builder().user_agent("prometheus-exporter/0.1.0").build().expect("http client"),
-            refresh
+            client: Client::builder()
+                .user_agent("prometheus-exporter/0.1.0")
+                .connect_timeout(Duration::from_secs(options.connect_timeout))
+                .timeout(Duration::from_secs(options.request_timeout))
+                .build()
+                .expect("http client"),
+            refresh,
+            partition_refresh,
+            auto_materialize_refresh,
+            options: options.clone(),
+            collecting: Mutex::new(()),
+            collections: Cell::new(0),
+            last_error: RefCell::new(None)
         }
     }
 
+    /// The name of the Dagster deployment this exporter collects from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Collect metrics from Dagit. Scrapes which arrive while a collection is in flight wait for it to finish (up to
+    /// the scrape timeout) and share its outcome instead of starting their own
     pub async fn query(&self) -> Result<()> {
+        let collections = self.collections.get();
+        let wait = Duration::from_secs(self.options.scrape_timeout);
+        let Ok(_collecting) = timeout(wait, self.collecting.lock()).await else {
+            return Err(anyhow!("timed out after {}s waiting for the collection in flight", wait.as_secs()));
+        };
+        if self.collections.get() != collections {
+            return match self.last_error.borrow().as_ref() {
+                Some(e) => Err(anyhow!("{e}")),
+                None => Ok(())
+            };
+        }
+
+        let result = self.collect().await;
+        self.collections.set(collections + 1);
+        *self.last_error.borrow_mut() = result.as_ref().err().map(ToString::to_string);
+
+        let m = self.metrics.borrow();
+        match &result {
+            Ok(()) => {
+                m.exporter_collection_success.set(1);
+                m.exporter_last_success_timestamp.set(now());
+            }
+            Err(e) => {
+                let kind = e.downcast_ref::<QueryError>().map_or("other", |e| e.kind);
+                m.exporter_collection_success.set(0);
+                m.exporter_collection_errors_total.get_or_create(&CollectionErrorLabel::new(kind)).inc();
+            }
+        }
+        result
+    }
+
+    /// The last collected metrics are still served while collections fail, until they are older than the max
+    /// staleness. Until the first collection succeeds, they are as old as the exporter.
+    pub fn check_staleness(&self) -> Result<()> {
+        let Some(max_staleness) = self.options.max_staleness else { return Ok(()) };
+
+        let m = self.metrics.borrow();
+        let last_success = match m.exporter_last_success_timestamp.get() {
+            t if t > 0.0 => t,
+            _ => m.started
+        };
+        let staleness = now() - last_success;
+        if staleness <= max_staleness as f64 {
+            return Ok(());
+        }
+
+        let last_error = self.last_error.borrow();
+        Err(anyhow!(
+            "metrics haven't been collected from Dagit for {staleness:.0}s: {}",
+            last_error.as_deref().unwrap_or("no collection has finished yet")
+        ))
+    }
+
+    async fn collect(&self) -> Result<()> {
         if !*self.refresh.borrow() {
+            self.query_partitions().await;
             return Ok(());
         }
 
-        let vars = dagit_query::Variables { concurrency_metrics: true, runs_since: self.metrics.borrow().cursor };
+        let vars = dagit_query::Variables { concurrency_metrics: true };
+
+        let resp = self.post::<DagitQuery>(vars).await?;
+
+        {
+            let mut m = self.metrics.borrow_mut();
+            m.exporter_last_scrape_timestamp.set(now());
+            m.timed("in_progress_runs", |m| m.set_in_progress_run_metrics(resp.in_progress_runs));
+            m.timed("workspace", |m| m.set_workspace_metrics(resp.workspace_or_error));
+            m.timed("daemons", |m| m.set_daemon_metrics(resp.instance.daemon_health));
+
+            if true {
+                m.timed("concurrency", |m| m.set_concurrency_metrics(resp.instance.concurrency_limits))
+            }
+        }
+
+        self.query_runs().await?;
+        self.metrics.borrow().clear_stale_metrics();
+
+        if self.options.backfill_limit > 0 {
+            let vars = backfill_query::Variables { backfill_limit: self.options.backfill_limit };
+            if let Some(resp) = self.post_section::<BackfillQuery>("backfills", vars).await {
+                self.metrics
+                    .borrow_mut()
+                    .timed("backfills", |m| m.set_backfill_metrics(resp.partition_backfills_or_error));
+            }
+        }
+
+        if self.options.auto_materialize_metrics {
+            let vars = auto_materialize_paused_query::Variables;
+            if let Some(resp) = self.post_section::<AutoMaterializePausedQuery>("auto_materialize", vars).await {
+                self.metrics.borrow().set_auto_materialize_metrics(resp.instance.auto_materialize_paused);
+            }
+        }
 
-        let resp = post_graphql::<DagitQuery, _>(&self.client, &self.url, vars)
-            .await
-            .map_err(anyhow::Error::msg)?
-            .data
-            .ok_or_else(|| anyhow!("empty data"))?;
+        // Assets are only queried for the metrics which need them, since their stale statuses are expensive. Their
+        // auto-materialize evaluations are queried one asset at a time, so they are on their own refresh period
+        let auto_materialize = self.auto_materialize_refresh.as_ref().filter(|r| *r.borrow());
+        let mut auto_materialized = Vec::new();
+        if self.options.asset_metrics || auto_materialize.is_some() {
+            let vars = asset_query::Variables { asset_metrics: self.options.asset_metrics };
+            if let Some(resp) = self.post_section::<AssetQuery>("assets", vars).await {
+                auto_materialized = resp
+                    .asset_nodes
+                    .iter()
+                    .filter(|a| auto_materialize.is_some() && a.auto_materialize_policy.is_some())
+                    .map(|a| (a.asset_key.path.clone(), a.group_name.clone()))
+                    .collect();
+                if self.options.asset_metrics {
+                    self.metrics.borrow_mut().timed("assets", |m| m.set_asset_metrics(resp.asset_nodes));
+                }
+            }
+        }
+        self.query_partitions().await;
 
-        let mut m = self.metrics.borrow_mut();
-        m.set_run_metrics(resp.runs_or_error);
-        m.set_workspace_metrics(resp.workspace_or_error);
-        m.set_daemon_metrics(resp.instance.daemon_health);
+        if let Some(refresh) = auto_materialize {
+            self.metrics.borrow().auto_materialize_last_evaluation_seconds.clear();
+            for (path, group) in auto_materialized {
+                self.query_auto_materialize(path, group).await;
+            }
+            *refresh.borrow_mut() = false;
+        }
 
-        if true {
-            m.set_concurrency_metrics(resp.instance.concurrency_limits)
+        // Mining event logs is slow, so only so many runs are mined per collection
+        let run_ids = self.metrics.borrow().next_event_log_runs(self.options.event_log_runs_per_collection);
+        for run_id in run_ids {
+            self.query_event_log(&run_id).await;
         }
 
         *self.refresh.borrow_mut() = false;
         Ok(())
     }
 
-    pub fn encode(&self) -> Result<Bytes, fmt::Error> {
-        let mut buffer = BytesMut::new();
-        prom_encode(&mut buffer, &self.registry).map(|()| buffer.freeze())
+    /// Partitioned asset metrics are queried separately, on their own (slower) refresh period. A failure is only
+    /// reported as an error of the section, and retried on the next refresh
+    async fn query_partitions(&self) {
+        let Some(refresh) = self.partition_refresh.as_ref().filter(|r| *r.borrow()) else {
+            return;
+        };
+
+        let Some(resp) = self.post_section::<AssetPartitionQuery>("partitions", asset_partition_query::Variables).await
+        else {
+            return;
+        };
+        self.metrics.borrow_mut().timed("partitions", |m| m.set_partition_metrics(resp.asset_nodes));
+
+        *refresh.borrow_mut() = false;
+    }
+
+    /// Page through the runs which reached a terminal state since the last refresh. If a page cannot be fetched, the
+    /// pages which were already processed are not fetched again on the next refresh
+    async fn query_runs(&self) -> Result<()> {
+        loop {
+            let vars = {
+                let m = self.metrics.borrow();
+                runs_query::Variables {
+                    runs_since: m.cursor,
+                    cursor: m.runs_page_cursor.clone(),
+                    limit: self.options.runs_page_size
+                }
+            };
+
+            let resp = self.post::<RunsQuery>(vars).await?;
+
+            let page_size = self.options.runs_page_size;
+            if !self.metrics.borrow_mut().timed("runs", |m| m.set_run_metrics(resp.runs_or_error, page_size)) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Auto-materialize evaluations can only be queried one asset at a time. An asset whose evaluations can't be
+    /// queried is reported as an error of the section, without keeping the other assets from being queried
+    async fn query_auto_materialize(&self, path: Vec<String>, group: Option<String>) {
+        let label = AssetLabel::new(&path, group);
+        let vars = auto_materialize_query::Variables { asset_key: auto_materialize_query::AssetKeyInput { path } };
+
+        let Some(resp) = self.post_section::<AutoMaterializeQuery>("auto_materialize_evaluations", vars).await else {
+            return;
+        };
+        if let Some(evaluations) = resp.auto_materialize_asset_evaluations_or_error {
+            self.metrics.borrow_mut().set_auto_materialize_evaluation_metrics(label, evaluations);
+        }
+    }
+
+    /// Page through a completed run's event log. If a page cannot be fetched, it is reported as an error of the
+    /// section and the run's cursor is kept so the remaining pages are fetched on a later refresh
+    async fn query_event_log(&self, run_id: &str) {
+        loop {
+            let after_cursor = self.metrics.borrow().event_log_runs.get(run_id).and_then(|r| r.cursor.clone());
+            let vars = logs_for_run_query::Variables { run_id: run_id.to_owned(), after_cursor };
+
+            let Some(resp) = self.post_section::<LogsForRunQuery>("event_log", vars).await else {
+                self.metrics.borrow_mut().event_log_failed(run_id);
+                return;
+            };
+            if !self.metrics.borrow_mut().set_event_log_metrics(run_id, resp.logs_for_run) {
+                return;
+            }
+        }
+    }
+
+    /// Sends a query for an optional section of the metrics, whose failure is reported as an error of the section
+    /// rather than failing the whole collection
+    async fn post_section<Q: GraphQLQuery>(&self, section: &str, vars: Q::Variables) -> Option<Q::ResponseData> {
+        match self.post::<Q>(vars).await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                let kind = e.downcast_ref::<QueryError>().map_or("other", |e| e.kind);
+                self.metrics.borrow().section_error(section, kind, None, &e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Sends a query to Dagit, observing how long it takes, how big its response is and how many errors it reports
+    async fn post<Q: GraphQLQuery>(&self, vars: Q::Variables) -> Result<Q::ResponseData> {
+        let body = Q::build_query(vars);
+        let label = QueryLabel::new(body.operation_name);
+
+        let start = Instant::now();
+        let resp = self.client.post(&self.url).json(&body).send().await.map_err(QueryError::from)?;
+        // A gateway's error page is no GraphQL response, so it is reported by its status rather than failing to decode
+        let resp = resp.error_for_status().map_err(QueryError::from)?;
+        let bytes = resp.bytes().await.map_err(QueryError::from)?;
+        {
+            let m = self.metrics.borrow();
+            m.exporter_graphql_request_duration_seconds.get_or_create(&label).observe(start.elapsed().as_secs_f64());
+            m.exporter_graphql_response_size_bytes.get_or_create(&label).observe(bytes.len() as f64);
+        }
+
+        let resp: Response<Q::ResponseData> = serde_json::from_slice(&bytes).map_err(QueryError::from)?;
+        if let Some(errors) = &resp.errors {
+            let m = self.metrics.borrow();
+            m.exporter_graphql_errors_total.get_or_create(&label).inc_by(errors.len() as u64);
+            // An error's path starts with the field of the response it broke
+            for e in errors {
+                let section = match e.path.as_deref() {
+                    Some([PathFragment::Key(field), ..]) => field.as_str(),
+                    _ => body.operation_name
+                };
+                m.section_error(section, "GraphQLError", None, &e.message);
+            }
+        }
+        Ok(resp.data.ok_or_else(|| QueryError { kind: "empty_data", message: "empty data".to_owned() })?)
+    }
+
+    pub fn save_state(&self) -> Result<()> {
+        match &self.options.state_file {
+            Some(path) => self.metrics.borrow().state().save(path),
+            None => Ok(())
+        }
+    }
+}
+
+/// Encodes the metrics of several exporters as a single exposition. Each exporter's registry encodes every metric
+/// family on its own, but a family may only appear once in the exposition, so the samples of the families they share
+/// are gathered under the first exporter's descriptors.
+pub fn encode(exporters: &[Rc<Exporter>]) -> Result<Bytes, fmt::Error> {
+    let mut families: Vec<String> = Vec::new();
+    let mut family_index: HashMap<String, usize> = HashMap::new();
+
+    for exporter in exporters {
+        let mut encoded = String::new();
+        prom_encode(&mut encoded, &exporter.registry)?;
+
+        // Which family the following lines belong to, and whether its descriptors were already written
+        let mut family = None;
+        for line in encoded.lines() {
+            if let Some(descriptor) = line.strip_prefix("# HELP ") {
+                let name = descriptor.split(' ').next().unwrap_or_default();
+                family = Some(match family_index.get(name) {
+                    Some(&i) => (i, true),
+                    None => {
+                        family_index.insert(name.to_owned(), families.len());
+                        families.push(String::new());
+                        (families.len() - 1, false)
+                    }
+                });
+            }
+            let Some((i, described)) = family else { continue };
+            if line.starts_with('#') && (described || line == "# EOF") {
+                continue;
+            }
+            families[i].push_str(line);
+            families[i].push('\n');
+        }
+    }
+
+    let mut buffer = BytesMut::new();
+    for family in families {
+        buffer.extend_from_slice(family.as_bytes());
     }
+    buffer.extend_from_slice(b"# EOF\n");
+    Ok(buffer.freeze())
 }
 
+/// A failed query to Dagit, classified by the kind of failure for the collection errors metric
+#[derive(Debug)]
+struct QueryError {
+    kind: &'static str,
+    message: String
+}
+
+impl From<reqwest::Error> for QueryError {
+    fn from(e: reqwest::Error) -> Self {
+        let kind = if e.is_timeout() {
+            "timeout"
+        } else if e.is_connect() {
+            "connect"
+        } else if e.is_status() {
+            "status"
+        } else if e.is_decode() {
+            "decode"
+        } else {
+            "request"
+        };
+        Self { kind, message: e.to_string() }
+    }
+}
+
+impl From<serde_json::Error> for QueryError {
+    fn from(e: serde_json::Error) -> Self {
+        Self { kind: "decode", message: e.to_string() }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     query_path = "graphql/dagit_query.graphql",
@@ -77,3 +430,92 @@ impl Exporter {
     response_derives = "Debug,PartialEq"
 )]
 struct DagitQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/runs_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct RunsQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/asset_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct AssetQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/backfill_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct BackfillQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/asset_partition_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct AssetPartitionQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/auto_materialize_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct AutoMaterializeQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/auto_materialize_paused_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct AutoMaterializePausedQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    query_path = "graphql/logs_for_run_query.graphql",
+    schema_path = "graphql/dagit_schema.graphql",
+    response_derives = "Debug,PartialEq"
+)]
+struct LogsForRunQuery;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter(name: &str) -> Rc<Exporter> {
+        let refresh = Rc::new(RefCell::new(true));
+        Rc::new(Exporter::new(name.to_owned(), String::new(), refresh, None, None, &Options::default()))
+    }
+
+    #[test]
+    fn encode_single_registry() {
+        let a = exporter("a");
+        let mut expected = String::new();
+        prom_encode(&mut expected, &a.registry).unwrap();
+        assert_eq!(encode(&[a]).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn encode_merges_families() {
+        let encoded = encode(&[exporter("a"), exporter("b")]).unwrap();
+        let encoded = std::str::from_utf8(&encoded).unwrap();
+
+        assert_eq!(encoded.matches("# HELP exporter_collection_success ").count(), 1);
+        assert!(encoded.contains(concat!(
+            "# TYPE exporter_collection_success gauge\n",
+            "exporter_collection_success{instance=\"a\"} 0\n",
+            "exporter_collection_success{instance=\"b\"} 0\n"
+        )));
+        assert_eq!(encoded.matches("# EOF").count(), 1);
+        assert!(encoded.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,184 @@
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// The most ticks which are ever counted between two timestamps, so a frequent schedule which has
+/// been silent for a long time doesn't iterate over its entire history on every refresh
+const MAX_TICKS: usize = 1000;
+
+/// A Dagster schedule's cron expression, evaluated in the schedule's execution timezone
+pub(super) struct CronSchedule {
+    /// The schedule ticks whenever any of these do
+    schedules: Vec<Schedule>,
+    timezone: Tz
+}
+
+impl CronSchedule {
+    /// Dagster takes standard 5-field crontab expressions (or @-macros), while the cron crate wants a
+    /// leading seconds field and numbers its days of the week from 1 (Sunday) rather than 0.
+    ///
+    /// Like crontab, Dagster ticks when either the day of the month or the day of the week matches if both are
+    /// restricted, whereas the cron crate wants both to match. That case is evaluated as two schedules.
+    pub(super) fn parse(expression: &str, timezone: Option<&str>) -> Option<Self> {
+        let expressions = if expression.trim_start().starts_with('@') {
+            vec![expression.trim().to_owned()]
+        } else {
+            let fields: Vec<&str> = expression.split_whitespace().collect();
+            let [minute, hour, day, month, weekday] = fields.as_slice() else {
+                return None;
+            };
+            let weekday = day_of_week(weekday)?;
+            if is_unrestricted(day) || is_unrestricted(&weekday) {
+                vec![format!("0 {minute} {hour} {day} {month} {weekday}")]
+            } else {
+                vec![format!("0 {minute} {hour} {day} {month} *"), format!("0 {minute} {hour} * {month} {weekday}")]
+            }
+        };
+
+        Some(Self {
+            schedules: expressions.iter().map(|e| Schedule::from_str(e).ok()).collect::<Option<_>>()?,
+            timezone: timezone.map_or(Some(Tz::UTC), |tz| tz.parse().ok())?
+        })
+    }
+
+    /// The unix seconds of the latest tick at or before the given unix seconds
+    pub(super) fn last_tick(&self, until: f64) -> Option<f64> {
+        let after = self.datetime(until + 1.0)?;
+        self.schedules.iter().filter_map(|s| s.after(&after).next_back()).map(|t| t.timestamp()).max().map(|t| t as f64)
+    }
+
+    /// The number of ticks after `since` and at or before `until` (in unix seconds)
+    pub(super) fn ticks_between(&self, since: f64, until: f64) -> usize {
+        let Some(since) = self.datetime(since) else { return 0 };
+        // The schedules of a union may tick at the same time, which only counts once
+        let ticks: BTreeSet<i64> = self
+            .schedules
+            .iter()
+            .flat_map(|s| s.after(&since).take(MAX_TICKS).map(|t| t.timestamp()).take_while(|t| *t as f64 <= until))
+            .collect();
+        ticks.len().min(MAX_TICKS)
+    }
+
+    fn datetime(&self, secs: f64) -> Option<DateTime<Tz>> {
+        Utc.timestamp_opt(secs as i64, 0).single().map(|t| t.with_timezone(&self.timezone))
+    }
+}
+
+fn is_unrestricted(field: &str) -> bool {
+    field == "*" || field == "?"
+}
+
+/// Expands a crontab day-of-week field, whose days are numbered from 0 or 7 (Sunday) or named, into the days of
+/// the week it matches by name, since the cron crate numbers them differently and has no day 7
+fn day_of_week(field: &str) -> Option<String> {
+    const DAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+    if is_unrestricted(field) {
+        return Some(field.to_owned());
+    }
+    let day = |s: &str| match s.parse::<usize>() {
+        Ok(i) if i <= 7 => Some(i),
+        Ok(_) => None,
+        Err(_) => DAYS.iter().position(|d| d.eq_ignore_ascii_case(s))
+    };
+
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|s| *s > 0)?),
+            None => (item, 1)
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (day(start)?, day(end)?),
+            // A single day with a step stands for the range from that day to the end of the week
+            None if step > 1 => (day(range)?, 6),
+            None => (day(range)?, day(range)?)
+        };
+        if start > end {
+            return None;
+        }
+        for d in (start..=end).step_by(step) {
+            days[d % 7] = true;
+        }
+    }
+    Some(DAYS.iter().zip(days).filter(|(_, matches)| *matches).map(|(d, _)| *d).collect::<Vec<_>>().join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(y: i32, m: u32, d: u32, h: u32) -> f64 {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap().timestamp() as f64
+    }
+
+    fn last_tick(expression: &str, timezone: Option<&str>, until: f64) -> Option<f64> {
+        CronSchedule::parse(expression, timezone).expect("valid cron schedule").last_tick(until)
+    }
+
+    #[test]
+    fn weekday_ranges() {
+        // 2023-06-04 is a Sunday
+        assert_eq!(last_tick("0 0 * * 1-5", None, unix(2023, 6, 4, 12)), Some(unix(2023, 6, 2, 0)));
+        assert_eq!(last_tick("0 0 * * MON-FRI", None, unix(2023, 6, 4, 12)), Some(unix(2023, 6, 2, 0)));
+        assert_eq!(last_tick("0 0 * * 1-5/2", None, unix(2023, 6, 4, 12)), Some(unix(2023, 6, 2, 0)));
+        assert_eq!(last_tick("0 0 * * 0,3", None, unix(2023, 6, 3, 12)), Some(unix(2023, 5, 31, 0)));
+
+        let schedule = CronSchedule::parse("0 0 * * 1-5", None).unwrap();
+        assert_eq!(schedule.ticks_between(unix(2023, 5, 28, 12), unix(2023, 6, 4, 12)), 5);
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        assert_eq!(last_tick("0 0 * * 7", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 4, 0)));
+        assert_eq!(last_tick("0 0 * * 0", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 4, 0)));
+
+        let schedule = CronSchedule::parse("0 0 * * 5-7", None).unwrap();
+        assert_eq!(schedule.ticks_between(unix(2023, 5, 29, 0), unix(2023, 6, 5, 0)), 3);
+    }
+
+    #[test]
+    fn macros() {
+        assert_eq!(last_tick("@daily", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 5, 0)));
+        assert_eq!(last_tick("@hourly", None, unix(2023, 6, 5, 12) + 60.0), Some(unix(2023, 6, 5, 12)));
+        assert_eq!(last_tick("@weekly", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 4, 0)));
+        assert_eq!(last_tick("@monthly", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 1, 0)));
+    }
+
+    #[test]
+    fn dst_timezones() {
+        // New York moved from UTC-5 to UTC-4 on 2023-03-12
+        let tz = Some("America/New_York");
+        assert_eq!(last_tick("0 9 * * *", tz, unix(2023, 3, 12, 0)), Some(unix(2023, 3, 11, 14)));
+        assert_eq!(last_tick("0 9 * * *", tz, unix(2023, 3, 13, 0)), Some(unix(2023, 3, 12, 13)));
+
+        let schedule = CronSchedule::parse("0 9 * * *", tz).unwrap();
+        assert_eq!(schedule.ticks_between(unix(2023, 3, 10, 0), unix(2023, 3, 14, 0)), 4);
+    }
+
+    #[test]
+    fn day_of_month_or_week() {
+        // Ticks on the 1st of the month as well as on every Monday
+        assert_eq!(last_tick("0 0 1 * 1", None, unix(2023, 6, 5, 12)), Some(unix(2023, 6, 5, 0)));
+        assert_eq!(last_tick("0 0 1 * 1", None, unix(2023, 6, 4, 12)), Some(unix(2023, 6, 1, 0)));
+
+        // 2023-05-01 is a Monday, which is only counted once
+        let schedule = CronSchedule::parse("0 0 1 * 1", None).unwrap();
+        assert_eq!(schedule.ticks_between(unix(2023, 4, 30, 0), unix(2023, 6, 5, 12)), 7);
+
+        // Unrestricted days of the month leave only the days of the week to match
+        assert_eq!(last_tick("0 0 * * 1", None, unix(2023, 6, 4, 12)), Some(unix(2023, 5, 29, 0)));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(CronSchedule::parse("0 0 * *", None).is_none());
+        assert!(CronSchedule::parse("0 0 * * 8", None).is_none());
+        assert!(CronSchedule::parse("0 0 * * 5-1", None).is_none());
+        assert!(CronSchedule::parse("0 0 * * *", Some("Mars/Olympus_Mons")).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::collections::HashMap;
+
+use super::labels::{RunLabel, StepLabel};
+#[allow(clippy::wildcard_imports)]
+use super::logs_for_run_query::*;
+use super::metrics::{now, Metrics};
+
+/// The progress of mining a completed run's event log, which may span several pages and refreshes
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct EventLogRun {
+    label: RunLabel,
+    steps: HashMap<String, StepLabel>,
+    pub(super) cursor: Option<String>,
+    /// Whether step latencies are measured
+    latencies: bool,
+    /// Whether failures are counted by their errors
+    errors: bool,
+    /// When each step last entered a phase whose latency is measured, keyed by the phase's starting event
+    #[serde(serialize_with = "serialize_pending", deserialize_with = "deserialize_pending")]
+    pending: HashMap<(String, Phase), f64>,
+    /// Whether any step failure has been counted, in which case the run failure only repeats it
+    step_failed: bool,
+    /// When the run was queued for mining, so the runs which have waited longest are mined first
+    queued: f64,
+    /// How many times querying the run's event log has failed
+    failures: u32
+}
+
+impl EventLogRun {
+    pub(super) fn new(label: RunLabel, steps: HashMap<String, StepLabel>, latencies: bool, errors: bool) -> Self {
+        Self {
+            label,
+            steps,
+            cursor: None,
+            latencies,
+            errors,
+            pending: HashMap::new(),
+            step_failed: false,
+            queued: now(),
+            failures: 0
+        }
+    }
+
+    /// Splits an error into its own class and the class at the bottom of its chain of causes
+    fn error_classes(error: Option<FailureError>) -> (Option<String>, Option<String>) {
+        let Some(error) = error else { return (None, None) };
+        let root_cause = match error.causes.into_iter().last() {
+            Some(cause) => cause.class_name,
+            None => error.class_name.clone()
+        };
+        (error.class_name, root_cause)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+enum Phase {
+    WorkerStartup,
+    ResourceInit,
+    RetryDelay
+}
+
+// JSON objects can only be keyed by strings, so pending phases are saved as a list of entries instead
+fn serialize_pending<S: Serializer>(pending: &HashMap<(String, Phase), f64>, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(pending)
+}
+
+fn deserialize_pending<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<(String, Phase), f64>, D::Error> {
+    Ok(Vec::<((String, Phase), f64)>::deserialize(d)?.into_iter().collect())
+}
+
+impl Metrics {
+    /// Queues a run for mining. Once the queue is full, the run which has waited longest is dropped to make room,
+    /// which is reported as an error of the section since its metrics are lost
+    pub(super) fn queue_event_log_run(&mut self, run_id: String, run: EventLogRun) {
+        self.event_log_runs.insert(run_id, run);
+        while self.event_log_runs.len() > self.event_log_queue_size {
+            let oldest = self.event_log_runs.iter().min_by(|a, b| a.1.queued.total_cmp(&b.1.queued)).map(|(id, _)| id);
+            let Some(oldest) = oldest.cloned() else { break };
+            self.event_log_runs.remove(&oldest);
+            self.section_error("event_log", "QueueFull", None, &format!("dropped run {oldest} without mining it"));
+        }
+    }
+
+    /// The runs whose event logs should be mined next, at most `limit` of them
+    pub(super) fn next_event_log_runs(&self, limit: usize) -> Vec<String> {
+        let mut runs: Vec<_> = self.event_log_runs.iter().map(|(id, run)| (run.queued, id)).collect();
+        runs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        runs.into_iter().take(limit).map(|(_, id)| id.clone()).collect()
+    }
+
+    /// Records a failure to query a run's event log, giving up on the run once it has run out of retries so that
+    /// it doesn't keep failing forever
+    pub(super) fn event_log_failed(&mut self, run_id: &str) {
+        let Some(run) = self.event_log_runs.get_mut(run_id) else { return };
+        run.failures += 1;
+        if run.failures > self.event_log_retries {
+            eprintln!("Giving up on the event log of run {run_id} after {} failures", run.failures);
+            self.event_log_runs.remove(run_id);
+        }
+    }
+
+    /// Returns whether there are more events left to page through for the run
+    pub(super) fn set_event_log_metrics(&mut self, run_id: &str, logs: LogsForRunQueryLogsForRun) -> bool {
+        use LogsForRunQueryLogsForRun::{EventConnection, PythonError, RunNotFoundError};
+
+        let (error_type, class_name, message) = match logs {
+            EventConnection(conn) => return self.set_event_log_page(run_id, conn),
+            PythonError(e) => ("PythonError", e.class_name, e.message),
+            RunNotFoundError(e) => ("RunNotFoundError", None, e.message)
+        };
+        self.section_error("event_log", error_type, class_name.as_deref(), &message);
+        self.event_log_runs.remove(run_id);
+        false
+    }
+
+    fn set_event_log_page(&mut self, run_id: &str, conn: LogsForRunQueryLogsForRunOnEventConnection) -> bool {
+        use LogsForRunQueryLogsForRunOnEventConnectionEvents::{
+            ExecutionStepFailureEvent, ExecutionStepRestartEvent, ExecutionStepUpForRetryEvent,
+            ResourceInitFailureEvent, ResourceInitStartedEvent, ResourceInitSuccessEvent, RunFailureEvent,
+            StepWorkerStartedEvent, StepWorkerStartingEvent
+        };
+
+        let Some(run) = self.event_log_runs.get_mut(run_id) else { return false };
+
+        for event in conn.events {
+            let (phase, started, e) = match event {
+                StepWorkerStartingEvent(e) => (Phase::WorkerStartup, true, e),
+                StepWorkerStartedEvent(e) => (Phase::WorkerStartup, false, e),
+                ResourceInitStartedEvent(e) => (Phase::ResourceInit, true, e),
+                ResourceInitSuccessEvent(e) | ResourceInitFailureEvent(e) => (Phase::ResourceInit, false, e),
+                ExecutionStepUpForRetryEvent(e) => (Phase::RetryDelay, true, e),
+                ExecutionStepRestartEvent(e) => (Phase::RetryDelay, false, e),
+                ExecutionStepFailureEvent(e) if run.errors => {
+                    let (error_class, root_cause) = EventLogRun::error_classes(e.error);
+                    self.run_failures_total
+                        .get_or_create(&run.label.failure_label(e.step_key, error_class, root_cause))
+                        .inc();
+                    run.step_failed = true;
+                    continue;
+                }
+                // A run which failed because of its steps carries no error of its own, so only failures which
+                // happened outside of any step (or without any step failing) are counted for the run itself
+                RunFailureEvent(e) if run.errors => {
+                    if e.error.is_some() || !run.step_failed {
+                        let (error_class, root_cause) = EventLogRun::error_classes(e.error);
+                        self.run_failures_total
+                            .get_or_create(&run.label.failure_label(None, error_class, root_cause))
+                            .inc();
+                    }
+                    continue;
+                }
+                _ => continue
+            };
+            if !run.latencies {
+                continue;
+            }
+            // Event timestamps are unix milliseconds
+            let (Some(step_key), Ok(millis)) = (e.step_key, e.timestamp.parse::<f64>()) else { continue };
+            let timestamp = millis / 1000.0;
+
+            if started {
+                run.pending.insert((step_key, phase), timestamp);
+                continue;
+            }
+            let Some(start) = run.pending.remove(&(step_key.clone(), phase)) else { continue };
+
+            let label = match run.steps.get(&step_key) {
+                Some(label) => label.clone(),
+                None => run.label.step_label(step_key, None)
+            };
+            let histogram = match phase {
+                Phase::WorkerStartup => &self.step_worker_startup_histogram,
+                Phase::ResourceInit => &self.step_resource_init_histogram,
+                Phase::RetryDelay => &self.step_retry_delay_histogram
+            };
+            histogram.get_or_create(&label).observe(timestamp - start);
+        }
+
+        run.cursor = Some(conn.cursor);
+        if !conn.has_more {
+            self.event_log_runs.remove(run_id);
+        }
+        conn.has_more
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    use super::super::labels::CommonLabel;
+
+    fn run(queued: f64) -> EventLogRun {
+        let common = CommonLabel::new(None, "job".to_owned(), Vec::new());
+        let label = RunLabel::new("SUCCESS".to_owned(), "default".to_owned(), common);
+        EventLogRun { queued, ..EventLogRun::new(label, HashMap::new(), true, false) }
+    }
+
+    #[test]
+    fn queue_drops_oldest() {
+        let mut m = Metrics::new("test", &Options { event_log_queue_size: 2, ..Options::default() });
+        m.queue_event_log_run("b".to_owned(), run(2.0));
+        m.queue_event_log_run("a".to_owned(), run(1.0));
+        m.queue_event_log_run("c".to_owned(), run(3.0));
+        assert_eq!(m.next_event_log_runs(10), ["b", "c"]);
+        assert_eq!(m.next_event_log_runs(1), ["b"]);
+    }
+}
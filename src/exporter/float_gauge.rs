@@ -1,31 +1,33 @@
-use parking_lot::MappedRwLockReadGuard;
+use parking_lot::{MappedRwLockReadGuard, Mutex};
 use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
-use prometheus_client::metrics::{family::Family, gauge, MetricType};
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::{gauge, MetricType};
 
+use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
 use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use super::metrics::now;
 
 /// Type alias for a Metric implementation for a float-64 prometheus Gauge.
 /// Ironically, it was meant to provide more shorthand but now I have to implement all the important
 /// traits just to delegate them to the wrapped inner type implementations...
-pub(super) struct GaugeF<L>(Family<L, InnerFloat>);
-type InnerFloat = gauge::Gauge<f64, AtomicU64>;
+pub(super) struct GaugeF<L>(Family<L, FloatGauge>);
+pub(super) type FloatGauge = gauge::Gauge<f64, AtomicU64>;
 
 impl<L: Clone + Hash + Eq + PartialEq> GaugeF<L> {
-    pub fn get_or_create(&self, label_set: &L) -> MappedRwLockReadGuard<InnerFloat> {
+    pub fn get_or_create(&self, label_set: &L) -> MappedRwLockReadGuard<'_, FloatGauge> {
         self.0.get_or_create(label_set)
     }
-    pub fn remove(&self, label_set: &L) -> bool {
-        self.0.remove(label_set)
-    }
     pub fn clear(&self) {
         self.0.clear()
     }
 }
 
 impl<L> Default for GaugeF<L>
-where Family<L, InnerFloat>: Default
+where Family<L, FloatGauge>: Default
 {
     fn default() -> Self {
         Self(Family::default())
@@ -33,7 +35,7 @@ where Family<L, InnerFloat>: Default
 }
 
 impl<L> Clone for GaugeF<L>
-where Family<L, InnerFloat>: Clone
+where Family<L, FloatGauge>: Clone
 {
     fn clone(&self) -> Self {
         Self(self.0.clone())
@@ -41,7 +43,7 @@ where Family<L, InnerFloat>: Clone
 }
 
 impl<L> EncodeMetric for GaugeF<L>
-where Family<L, InnerFloat>: EncodeMetric
+where Family<L, FloatGauge>: EncodeMetric
 {
     fn encode(&self, encoder: MetricEncoder<'_, '_>) -> fmt::Result {
         self.0.encode(encoder)
@@ -52,9 +54,101 @@ where Family<L, InnerFloat>: EncodeMetric
 }
 
 impl<L> fmt::Debug for GaugeF<L>
-where Family<L, InnerFloat>: fmt::Debug
+where Family<L, FloatGauge>: fmt::Debug
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.0.fmt(f)
     }
 }
+/// A metric family which keeps track of its label sets and when each was last updated, since prometheus-client
+/// offers no way to list them. This lets its metrics be saved and restored across restarts, and lets series which
+/// are no longer updated (e.g. for a renamed step) be removed without knowing all of their labels.
+pub(super) struct Tracked<L, M, C = fn() -> M> {
+    family: Family<L, M, C>,
+    updated: Arc<Mutex<HashMap<L, f64>>>
+}
+
+impl<L: Clone + Hash + Eq, M, C: MetricConstructor<M>> Tracked<L, M, C> {
+    pub(super) fn new(family: Family<L, M, C>) -> Self {
+        Self { family, updated: Arc::default() }
+    }
+
+    pub fn get_or_create(&self, label_set: &L) -> MappedRwLockReadGuard<'_, M> {
+        let mut updated = self.updated.lock();
+        match updated.get_mut(label_set) {
+            Some(t) => *t = now(),
+            None => {
+                updated.insert(label_set.clone(), now());
+            }
+        }
+        self.family.get_or_create(label_set)
+    }
+
+    /// Removes every label set matching the predicate, which may only look at some of its labels
+    pub(super) fn remove_matching(&self, matches: impl Fn(&L) -> bool) {
+        self.retain(|label_set, _| !matches(label_set));
+    }
+
+    /// Removes every label set which hasn't been updated since the given unix seconds
+    pub(super) fn remove_stale(&self, since: f64) {
+        self.retain(|_, updated| updated >= since);
+    }
+
+    fn retain(&self, keep: impl Fn(&L, f64) -> bool) {
+        self.updated.lock().retain(|label_set, updated| {
+            let kept = keep(label_set, *updated);
+            if !kept {
+                self.family.remove(label_set);
+            }
+            kept
+        });
+    }
+
+    /// Every label set, along with the given reading of its metric and the unix seconds it was last updated at
+    pub(super) fn snapshot<V>(&self, read: impl Fn(&M) -> V) -> Vec<(L, V, f64)> {
+        let updated = self.updated.lock();
+        updated.iter().map(|(l, t)| (l.clone(), read(&self.family.get_or_create(l)), *t)).collect()
+    }
+
+    /// Re-creates the metrics of a snapshot, writing each of their readings back with the given function. They keep
+    /// the update times of the snapshot, so that restarts don't keep stale metrics from expiring
+    pub(super) fn restore<V, R>(&self, snapshot: Vec<(L, V, f64)>, write: impl Fn(&M, V) -> R) {
+        for (label_set, value, updated) in snapshot {
+            write(&self.family.get_or_create(&label_set), value);
+            self.updated.lock().insert(label_set, updated);
+        }
+    }
+}
+
+impl<L: Clone + Hash + Eq, M: Default> Default for Tracked<L, M> {
+    fn default() -> Self {
+        Self::new(Family::default())
+    }
+}
+
+impl<L, M, C> Clone for Tracked<L, M, C>
+where Family<L, M, C>: Clone
+{
+    fn clone(&self) -> Self {
+        Self { family: self.family.clone(), updated: Arc::clone(&self.updated) }
+    }
+}
+
+impl<L, M, C> EncodeMetric for Tracked<L, M, C>
+where Family<L, M, C>: EncodeMetric
+{
+    fn encode(&self, encoder: MetricEncoder<'_, '_>) -> fmt::Result {
+        self.family.encode(encoder)
+    }
+    fn metric_type(&self) -> MetricType {
+        self.family.metric_type()
+    }
+}
+
+impl<L, M, C> fmt::Debug for Tracked<L, M, C>
+where Family<L, M, C>: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.family.fmt(f)
+    }
+}
@@ -0,0 +1,24 @@
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::histogram::Histogram;
+
+use std::hash::Hash;
+
+use super::float_gauge::Tracked;
+
+/// A family of histograms whose bucket boundaries are only known at runtime (i.e. from the CLI)
+pub(super) type HistogramFamily<L> = Tracked<L, Histogram, Buckets>;
+
+#[derive(Clone, Debug)]
+pub(super) struct Buckets(Vec<f64>);
+
+impl Buckets {
+    pub(super) fn family<L: Clone + Hash + Eq>(buckets: &[f64]) -> HistogramFamily<L> {
+        Tracked::new(Family::new_with_constructor(Self(buckets.to_vec())))
+    }
+}
+
+impl MetricConstructor<Histogram> for Buckets {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.0.iter().copied())
+    }
+}
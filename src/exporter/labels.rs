@@ -1,29 +1,53 @@
 use prometheus_client::encoding::EncodeLabelSet;
+use serde::{Deserialize, Serialize};
 
+use super::backfill_query::{self, BackfillQueryPartitionBackfillsOrErrorOnPartitionBackfillsResults};
 #[allow(clippy::wildcard_imports)]
 use super::dagit_query::*;
+use super::runs_query;
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct CommonLabel {
     workspace_location: Option<String>,
     repository_name: Option<String>,
-    pipeline_name: String
+    pipeline_name: String,
+    /// The label names and values of the allowlisted run tags, which must stay the last field to be flattened
+    #[prometheus(flatten)]
+    tags: Vec<(String, String)>
 }
 
 impl CommonLabel {
-    pub(super) fn new(repo: Option<DagitQueryRunsOrErrorOnRunsResultsRepositoryOrigin>, job: String) -> Self {
+    pub(super) fn new(repo: Option<RunOrigin>, job: String, tags: Vec<(String, String)>) -> Self {
         match repo {
             Some(r) => Self {
                 workspace_location: Some(r.repository_location_name),
                 repository_name: Some(r.repository_name),
-                pipeline_name: job
+                pipeline_name: job,
+                tags
             },
-            None => Self { workspace_location: None, repository_name: None, pipeline_name: job }
+            None => Self { workspace_location: None, repository_name: None, pipeline_name: job, tags }
         }
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+/// Terminal runs are paged through with their own query, which has its own copy of the fragment
+impl From<runs_query::RunOrigin> for RunOrigin {
+    fn from(r: runs_query::RunOrigin) -> Self {
+        Self {
+            repository_name: r.repository_name,
+            repository_location_name: r.repository_location_name
+        }
+    }
+}
+
+/// The Prometheus label name a run tag is exported as, since tag keys like `dagster/partition` aren't valid label
+/// names and could otherwise clash with the exporter's own labels
+pub(super) fn tag_label_name(key: &str) -> String {
+    let key: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("tag_{key}")
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct RunLabel {
     pub(super) status: String,
     mode: String,
@@ -35,23 +59,47 @@ impl RunLabel {
     pub(super) const fn new(status: String, mode: String, common: CommonLabel) -> Self {
         Self { status, mode, common }
     }
-    pub(super) fn step_label(&self, step_key: String, status: Option<StepEventStatus>) -> StepLabel {
+    /// Whether the other label is for the same runs, but in a different status
+    pub(super) fn differs_by_status(&self, other: &Self) -> bool {
+        self.status != other.status && self.mode == other.mode && self.common == other.common
+    }
+    pub(super) fn step_label(&self, step_key: String, status: Option<runs_query::StepEventStatus>) -> StepLabel {
         StepLabel { common: self.common.clone(), step_key, status: status.map(|x| format!("{x:?}")) }
     }
     pub(super) fn asset_label(
-        &self, step_key: Option<String>, asset_key: &DagitQueryRunsOrErrorOnRunsResultsAssetMaterializationsAssetKey,
+        &self, step_key: Option<String>,
+        asset_key: &runs_query::RunsQueryRunsOrErrorOnRunsResultsAssetMaterializationsAssetKey,
         partition: Option<String>
     ) -> MaterializationLabel {
         MaterializationLabel {
             common: self.common.clone(),
             step_key,
-            asset_key: asset_key.path.join("/"),
+            asset_key: join_asset_key(&asset_key.path),
             partition
         }
     }
+    pub(super) fn failure_label(
+        &self, step_key: Option<String>, error_class: Option<String>, root_cause_class: Option<String>
+    ) -> RunFailureLabel {
+        RunFailureLabel { common: self.common.clone(), step_key, error_class, root_cause_class }
+    }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+/// Flatten a multi-part asset key into a single label value
+fn join_asset_key(path: &[String]) -> String {
+    path.join("/")
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct RunFailureLabel {
+    step_key: Option<String>,
+    error_class: Option<String>,
+    root_cause_class: Option<String>,
+    #[prometheus(flatten)]
+    common: CommonLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct StepLabel {
     step_key: String,
     pub(super) status: Option<String>,
@@ -60,12 +108,16 @@ pub(super) struct StepLabel {
 }
 
 impl StepLabel {
+    /// Whether the other label is for the same step, but in a different status
+    pub(super) fn differs_by_status(&self, other: &Self) -> bool {
+        self.status != other.status && self.step_key == other.step_key && self.common == other.common
+    }
     pub(super) fn expectation_label(&self, label: Option<String>) -> ExpectationLabel {
         ExpectationLabel { common: self.common.clone(), step_key: self.step_key.clone(), label }
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct ExpectationLabel {
     step_key: String,
     label: Option<String>,
@@ -73,7 +125,7 @@ pub(super) struct ExpectationLabel {
     common: CommonLabel
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct MaterializationLabel {
     step_key: Option<String>,
     asset_key: String,
@@ -82,13 +134,169 @@ pub(super) struct MaterializationLabel {
     common: CommonLabel
 }
 
+impl MaterializationLabel {
+    pub(super) fn metadata_label(&self, metadata_label: String) -> MetadataLabel {
+        MetadataLabel { metadata_label, materialization: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct MetadataLabel {
+    metadata_label: String,
+    #[prometheus(flatten)]
+    materialization: MaterializationLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct AssetLabel {
+    asset_key: String,
+    group: Option<String>
+}
+
+impl AssetLabel {
+    pub(super) fn new(path: &[String], group: Option<String>) -> Self {
+        Self { asset_key: join_asset_key(path), group }
+    }
+    pub(super) fn state_label(&self, state: &str) -> AssetStateLabel {
+        AssetStateLabel { state: state.to_owned(), asset: self.clone() }
+    }
+    pub(super) fn decision_label(&self, decision: &str) -> AutoMaterializeLabel {
+        AutoMaterializeLabel { decision: decision.to_owned(), asset: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct AssetStateLabel {
+    state: String,
+    #[prometheus(flatten)]
+    asset: AssetLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct AutoMaterializeLabel {
+    decision: String,
+    #[prometheus(flatten)]
+    asset: AssetLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct BackfillLabel {
+    status: String,
+    partition_set: Option<String>,
+    asset_selection: Option<String>
+}
+
+impl BackfillLabel {
+    pub(super) fn new(backfill: &BackfillQueryPartitionBackfillsOrErrorOnPartitionBackfillsResults) -> Self {
+        Self {
+            status: format!("{:?}", backfill.status),
+            partition_set: backfill.partition_set_name.clone(),
+            asset_selection: backfill
+                .asset_selection
+                .as_ref()
+                .map(|keys| keys.iter().map(|k| join_asset_key(&k.path)).collect::<Vec<_>>().join(","))
+        }
+    }
+    pub(super) fn backfill_id_label(&self, backfill_id: String) -> BackfillIdLabel {
+        BackfillIdLabel { backfill_id, backfill: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct BackfillIdLabel {
+    backfill_id: String,
+    #[prometheus(flatten)]
+    backfill: BackfillLabel
+}
+
+impl BackfillIdLabel {
+    pub(super) fn run_status_label(&self, status: &backfill_query::RunStatus) -> BackfillRunLabel {
+        BackfillRunLabel { run_status: format!("{status:?}"), backfill: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct BackfillRunLabel {
+    run_status: String,
+    #[prometheus(flatten)]
+    backfill: BackfillIdLabel
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub(super) struct SensorLabel {
     workspace_location: String,
     repository_name: String,
     name: String,
-    sensor_type: String,
-    eval_interval: i32
+    sensor_type: String
+}
+
+impl SensorLabel {
+    pub(super) fn new(
+        workspace: String, repo: String,
+        sensor: &DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntriesLocationOrLoadErrorOnRepositoryLocationRepositoriesSensors
+    ) -> Self {
+        Self {
+            workspace_location: workspace,
+            repository_name: repo,
+            name: sensor.name.clone(),
+            sensor_type: format!("{:?}", sensor.sensor_type)
+        }
+    }
+    pub(super) fn target_label(&self, pipeline_name: String) -> SensorTargetLabel {
+        SensorTargetLabel { pipeline_name, sensor: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct SensorTargetLabel {
+    pipeline_name: String,
+    #[prometheus(flatten)]
+    sensor: SensorLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct CollectionErrorLabel {
+    kind: String
+}
+
+impl CollectionErrorLabel {
+    pub(super) fn new(kind: &str) -> Self {
+        Self { kind: kind.to_owned() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct QueryLabel {
+    query: String
+}
+
+impl QueryLabel {
+    pub(super) fn new(query: &str) -> Self {
+        Self { query: query.to_owned() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct SectionErrorLabel {
+    section: String,
+    error_type: String
+}
+
+impl SectionErrorLabel {
+    pub(super) fn new(section: &str, error_type: &str) -> Self {
+        Self { section: section.to_owned(), error_type: error_type.to_owned() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct SectionLabel {
+    section: String
+}
+
+impl SectionLabel {
+    pub(super) fn new(section: &str) -> Self {
+        Self { section: section.to_owned() }
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -126,6 +334,32 @@ impl WorkspaceLocationLabel {
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct WorkspaceLocationStateLabel {
+    workspace_location: String,
+    state: String
+}
+
+impl WorkspaceLocationStateLabel {
+    pub(super) fn new(workspace: &DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntries, state: &str) -> Self {
+        Self { workspace_location: workspace.name.clone(), state: state.to_owned() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct WorkspaceLocationErrorLabel {
+    workspace_location: String,
+    error_class: Option<String>
+}
+
+impl WorkspaceLocationErrorLabel {
+    pub(super) fn new(
+        workspace: &DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntries, error_class: Option<String>
+    ) -> Self {
+        Self { workspace_location: workspace.name.clone(), error_class }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
 pub(super) struct InstigationLabel {
     workspace_location: String,
     repository_name: String,
@@ -142,4 +376,24 @@ impl InstigationLabel {
             instigation_type: i_type
         }
     }
+    pub(super) fn tick_label(&self, status: &InstigationTickStatus) -> InstigationTickLabel {
+        InstigationTickLabel { status: format!("{status:?}"), instigation: self.clone() }
+    }
+    pub(super) fn error_label(&self, error_class: Option<String>) -> InstigationErrorLabel {
+        InstigationErrorLabel { error_class, instigation: self.clone() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+pub(super) struct InstigationTickLabel {
+    status: String,
+    #[prometheus(flatten)]
+    instigation: InstigationLabel
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(super) struct InstigationErrorLabel {
+    error_class: Option<String>,
+    #[prometheus(flatten)]
+    instigation: InstigationLabel
 }
@@ -1,31 +1,99 @@
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::metrics::{counter::Counter, family::Family, gauge::Gauge};
 use prometheus_client::registry::{Registry, Unit};
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::iter;
 use std::sync::atomic::AtomicU64;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use super::float_gauge::GaugeF;
+use super::event_log::EventLogRun;
+use super::float_gauge::{FloatGauge, GaugeF, Tracked};
+use super::histogram::{Buckets, HistogramFamily};
 use super::labels::{
-    DaemonStatusLabel, ExpectationLabel, InstigationLabel, MaterializationLabel, RunLabel, StepLabel,
-    WorkspaceLocationLabel
+    tag_label_name, AssetLabel, AssetStateLabel, AutoMaterializeLabel, BackfillIdLabel, BackfillLabel,
+    BackfillRunLabel, CollectionErrorLabel, DaemonStatusLabel, ExpectationLabel, InstigationErrorLabel,
+    InstigationLabel, InstigationTickLabel, MaterializationLabel, MetadataLabel, QueryLabel, RunFailureLabel, RunLabel,
+    SectionErrorLabel, SectionLabel, SensorLabel, SensorTargetLabel, StepLabel, WorkspaceLocationErrorLabel,
+    WorkspaceLocationLabel, WorkspaceLocationStateLabel
 };
+use super::seen_runs::SeenRuns;
+use super::Options;
 
 pub(super) struct Metrics {
     pub(super) cursor: f64,
+    /// Terminal runs are paged through newest first, so the cursor above only advances once the last page has been
+    /// processed, to the latest update time seen across every page. Until then, the ID of the last run processed
+    /// marks where to continue from, even if a later page fails and has to be fetched again on the next refresh.
+    pub(super) runs_page_cursor: Option<String>,
+    pub(super) runs_page_update_time: f64,
+    pub(super) started: f64,
+    pub(super) seen_runs: SeenRuns,
 
-    pub(super) run_total: Family<RunLabel, Counter>,
-    pub(super) run_duration_seconds: GaugeF<RunLabel>,
-    pub(super) run_queue_seconds: GaugeF<RunLabel>,
+    pub(super) run_total: Tracked<RunLabel, Counter>,
+    pub(super) run_failures_total: Tracked<RunFailureLabel, Counter>,
+    pub(super) run_duration_seconds: Tracked<RunLabel, FloatGauge>,
+    pub(super) run_queue_seconds: Tracked<RunLabel, FloatGauge>,
+    pub(super) run_duration_histogram: HistogramFamily<RunLabel>,
+    pub(super) run_queue_histogram: HistogramFamily<RunLabel>,
     pub(super) runs_by_instigation_total: Family<InstigationLabel, Gauge>,
+    pub(super) instigation_ticks_total: Tracked<InstigationTickLabel, Counter>,
+    pub(super) instigation_last_tick_seconds: GaugeF<InstigationLabel>,
+    pub(super) instigation_last_successful_tick_seconds: GaugeF<InstigationLabel>,
+    pub(super) instigation_last_tick_failed: Family<InstigationErrorLabel, Gauge>,
+    pub(super) tick_cursors: HashMap<InstigationLabel, f64>,
+    pub(super) schedule_last_expected_tick_timestamp_seconds: GaugeF<InstigationLabel>,
+    pub(super) schedule_last_actual_tick_timestamp_seconds: GaugeF<InstigationLabel>,
+    pub(super) schedule_missed_ticks: Family<InstigationLabel, Gauge>,
+    /// The schedules whose cron expressions couldn't be parsed, which are only reported once
+    pub(super) unparsable_schedules: HashSet<(InstigationLabel, String)>,
+    pub(super) sensor_running: Family<SensorLabel, Gauge>,
+    pub(super) sensor_next_tick_timestamp_seconds: GaugeF<SensorLabel>,
+    pub(super) sensor_min_interval_seconds: Family<SensorLabel, Gauge>,
+    pub(super) sensor_target_info: Family<SensorTargetLabel, Gauge>,
+    pub(super) runs_in_progress: Family<RunLabel, Gauge>,
+    pub(super) run_in_progress_oldest_age_seconds: GaugeF<RunLabel>,
 
-    pub(super) step_total: Family<StepLabel, Counter>,
-    pub(super) step_duration_seconds: GaugeF<StepLabel>,
-    pub(super) step_attempts: Family<StepLabel, Gauge>,
-    pub(super) expectation_failure: Family<ExpectationLabel, Gauge>,
-    pub(super) asset_materialization_timestamp: GaugeF<MaterializationLabel>,
+    pub(super) step_total: Tracked<StepLabel, Counter>,
+    pub(super) step_duration_seconds: Tracked<StepLabel, FloatGauge>,
+    pub(super) step_duration_histogram: HistogramFamily<StepLabel>,
+    pub(super) step_worker_startup_histogram: HistogramFamily<StepLabel>,
+    pub(super) step_resource_init_histogram: HistogramFamily<StepLabel>,
+    pub(super) step_retry_delay_histogram: HistogramFamily<StepLabel>,
+    pub(super) event_log_runs: HashMap<String, EventLogRun>,
+    pub(super) step_attempts: Tracked<StepLabel, Gauge>,
+    pub(super) expectation_failure: Tracked<ExpectationLabel, Gauge>,
+    pub(super) asset_materialization_timestamp: Tracked<MaterializationLabel, FloatGauge>,
+    pub(super) asset_metadata: Tracked<MetadataLabel, FloatGauge>,
+    pub(super) metadata_labels: HashSet<String>,
+    /// The allowlisted run tag keys, paired with the label names they are exported as
+    pub(super) run_tags: Vec<(String, String)>,
+
+    pub(super) asset_freshness_current_minutes_late: GaugeF<AssetLabel>,
+    pub(super) asset_freshness_latest_materialization_minutes_late: GaugeF<AssetLabel>,
+    pub(super) asset_freshness_maximum_lag_minutes: GaugeF<AssetLabel>,
+    pub(super) asset_stale_status: Family<AssetStateLabel, Gauge>,
+    pub(super) asset_partitions: Family<AssetStateLabel, Gauge>,
+    pub(super) asset_latest_partition_end_timestamp_seconds: GaugeF<AssetLabel>,
+
+    pub(super) auto_materialize_paused: Gauge,
+    pub(super) auto_materialize_evaluations_total: Tracked<AutoMaterializeLabel, Counter>,
+    pub(super) auto_materialize_last_evaluation_seconds: GaugeF<AssetLabel>,
+    pub(super) auto_materialize_cursors: HashMap<AssetLabel, i64>,
+
+    pub(super) backfill_total: Tracked<BackfillLabel, Counter>,
+    pub(super) backfill_partitions: Family<BackfillIdLabel, Gauge>,
+    pub(super) backfill_cancelable_partitions: Family<BackfillIdLabel, Gauge>,
+    pub(super) backfill_partition_runs: Family<BackfillRunLabel, Gauge>,
+    pub(super) backfill_age_seconds: GaugeF<BackfillIdLabel>,
+    pub(super) backfill_duration_seconds: GaugeF<BackfillIdLabel>,
+    pub(super) backfills_seen: HashSet<String>,
 
     pub(super) daemon_last_heartbeat_seconds: GaugeF<DaemonStatusLabel>,
     pub(super) workspace_location_last_update_seconds: GaugeF<WorkspaceLocationLabel>,
+    pub(super) workspace_location_status: Family<WorkspaceLocationStateLabel, Gauge>,
+    pub(super) workspace_location_load_error: Family<WorkspaceLocationErrorLabel, Gauge>,
 
     pub(super) concurrency_slots: Family<Vec<(String, String)>, Gauge>,
     pub(super) concurrency_active_slots: Family<Vec<(String, String)>, Gauge>,
@@ -33,29 +101,105 @@ pub(super) struct Metrics {
     pub(super) concurrency_assigned_steps: Family<Vec<(String, String)>, Gauge>,
 
     pub(super) exporter_last_scrape_runs: Gauge,
+    pub(super) exporter_duplicate_runs_total: Counter,
+    pub(super) exporter_collection_success: Gauge,
+    pub(super) exporter_collection_errors_total: Family<CollectionErrorLabel, Counter>,
+    pub(super) exporter_last_success_timestamp: Gauge<f64, AtomicU64>,
+    pub(super) exporter_graphql_request_duration_seconds: Family<QueryLabel, Histogram>,
+    pub(super) exporter_graphql_response_size_bytes: Family<QueryLabel, Histogram>,
+    pub(super) exporter_graphql_errors_total: Family<QueryLabel, Counter>,
+    pub(super) exporter_section_processing_duration_seconds: Family<SectionLabel, Histogram>,
+    pub(super) exporter_section_errors_total: Family<SectionErrorLabel, Counter>,
     pub(super) exporter_last_scrape_timestamp: Gauge<f64, AtomicU64>,
 
-    pub(super) concurrency_metrics: bool
+    /// The name and value of the label identifying the Dagster deployment on every metric
+    pub(super) instance: (String, String),
+    pub(super) schedule_tick_grace: f64,
+    pub(super) stuck_tick_timeout: f64,
+    pub(super) metric_ttl: Option<f64>,
+    pub(super) concurrency_metrics: bool,
+    pub(super) asset_metrics: bool,
+    pub(super) partition_metrics: bool,
+    pub(super) auto_materialize_metrics: bool,
+    pub(super) run_failure_metrics: bool,
+    pub(super) event_log_metrics: bool,
+    pub(super) event_log_queue_size: usize,
+    pub(super) event_log_retries: u32,
+    pub(super) histograms: bool
 }
 
 impl Metrics {
-    pub(super) fn new(concurrency_metrics: bool) -> Self {
+    pub(super) fn new(instance: &str, options: &Options) -> Self {
+        // Replaying history is only a matter of starting the cursor further back, since runs are paged through
+        let runs_since = now() - options.lookback.unwrap_or_default() as f64;
         Self {
-            cursor: SystemTime::now().duration_since(UNIX_EPOCH).expect("clock time").as_secs_f64(),
+            cursor: runs_since,
+            runs_page_cursor: None,
+            runs_page_update_time: runs_since,
+            started: now(),
+            seen_runs: SeenRuns::new(options.seen_runs_capacity, options.seen_runs_max_age as f64),
 
-            run_total: Family::<RunLabel, Counter>::default(),
-            run_duration_seconds: GaugeF::<RunLabel>::default(),
-            run_queue_seconds: GaugeF::<RunLabel>::default(),
+            run_total: Tracked::<RunLabel, Counter>::default(),
+            run_failures_total: Tracked::<RunFailureLabel, Counter>::default(),
+            run_duration_seconds: Tracked::<RunLabel, FloatGauge>::default(),
+            run_queue_seconds: Tracked::<RunLabel, FloatGauge>::default(),
+            run_duration_histogram: Buckets::family(&options.run_duration_buckets),
+            run_queue_histogram: Buckets::family(&options.run_queue_buckets),
             runs_by_instigation_total: Family::<InstigationLabel, Gauge>::default(),
+            instigation_ticks_total: Tracked::<InstigationTickLabel, Counter>::default(),
+            instigation_last_tick_seconds: GaugeF::<InstigationLabel>::default(),
+            instigation_last_successful_tick_seconds: GaugeF::<InstigationLabel>::default(),
+            instigation_last_tick_failed: Family::<InstigationErrorLabel, Gauge>::default(),
+            tick_cursors: HashMap::new(),
+            schedule_last_expected_tick_timestamp_seconds: GaugeF::<InstigationLabel>::default(),
+            schedule_last_actual_tick_timestamp_seconds: GaugeF::<InstigationLabel>::default(),
+            schedule_missed_ticks: Family::<InstigationLabel, Gauge>::default(),
+            unparsable_schedules: HashSet::new(),
+            sensor_running: Family::<SensorLabel, Gauge>::default(),
+            sensor_next_tick_timestamp_seconds: GaugeF::<SensorLabel>::default(),
+            sensor_min_interval_seconds: Family::<SensorLabel, Gauge>::default(),
+            sensor_target_info: Family::<SensorTargetLabel, Gauge>::default(),
+            runs_in_progress: Family::<RunLabel, Gauge>::default(),
+            run_in_progress_oldest_age_seconds: GaugeF::<RunLabel>::default(),
+
+            step_total: Tracked::<StepLabel, Counter>::default(),
+            step_duration_seconds: Tracked::<StepLabel, FloatGauge>::default(),
+            step_duration_histogram: Buckets::family(&options.step_duration_buckets),
+            step_worker_startup_histogram: Buckets::family(&options.step_duration_buckets),
+            step_resource_init_histogram: Buckets::family(&options.step_duration_buckets),
+            step_retry_delay_histogram: Buckets::family(&options.step_duration_buckets),
+            event_log_runs: HashMap::new(),
+            step_attempts: Tracked::<StepLabel, Gauge>::default(),
+            expectation_failure: Tracked::<ExpectationLabel, Gauge>::default(),
+            asset_materialization_timestamp: Tracked::<MaterializationLabel, FloatGauge>::default(),
+            asset_metadata: Tracked::<MetadataLabel, FloatGauge>::default(),
+            metadata_labels: options.metadata_labels.iter().cloned().collect(),
+            run_tags: run_tags(&options.run_tags),
 
-            step_total: Family::<StepLabel, Counter>::default(),
-            step_duration_seconds: GaugeF::<StepLabel>::default(),
-            step_attempts: Family::<StepLabel, Gauge>::default(),
-            expectation_failure: Family::<ExpectationLabel, Gauge>::default(),
-            asset_materialization_timestamp: GaugeF::<MaterializationLabel>::default(),
+            asset_freshness_current_minutes_late: GaugeF::<AssetLabel>::default(),
+            asset_freshness_latest_materialization_minutes_late: GaugeF::<AssetLabel>::default(),
+            asset_freshness_maximum_lag_minutes: GaugeF::<AssetLabel>::default(),
+            asset_stale_status: Family::<AssetStateLabel, Gauge>::default(),
+            asset_partitions: Family::<AssetStateLabel, Gauge>::default(),
+            asset_latest_partition_end_timestamp_seconds: GaugeF::<AssetLabel>::default(),
+
+            auto_materialize_paused: Gauge::default(),
+            auto_materialize_evaluations_total: Tracked::<AutoMaterializeLabel, Counter>::default(),
+            auto_materialize_last_evaluation_seconds: GaugeF::<AssetLabel>::default(),
+            auto_materialize_cursors: HashMap::new(),
+
+            backfill_total: Tracked::<BackfillLabel, Counter>::default(),
+            backfill_partitions: Family::<BackfillIdLabel, Gauge>::default(),
+            backfill_cancelable_partitions: Family::<BackfillIdLabel, Gauge>::default(),
+            backfill_partition_runs: Family::<BackfillRunLabel, Gauge>::default(),
+            backfill_age_seconds: GaugeF::<BackfillIdLabel>::default(),
+            backfill_duration_seconds: GaugeF::<BackfillIdLabel>::default(),
+            backfills_seen: HashSet::new(),
 
             daemon_last_heartbeat_seconds: GaugeF::<DaemonStatusLabel>::default(),
             workspace_location_last_update_seconds: GaugeF::<WorkspaceLocationLabel>::default(),
+            workspace_location_status: Family::<WorkspaceLocationStateLabel, Gauge>::default(),
+            workspace_location_load_error: Family::<WorkspaceLocationErrorLabel, Gauge>::default(),
 
             concurrency_slots: Family::<Vec<(String, String)>, Gauge>::default(),
             concurrency_active_slots: Family::<Vec<(String, String)>, Gauge>::default(),
@@ -63,19 +207,74 @@ impl Metrics {
             concurrency_assigned_steps: Family::<Vec<(String, String)>, Gauge>::default(),
 
             exporter_last_scrape_runs: Gauge::default(),
+            exporter_duplicate_runs_total: Counter::default(),
+            exporter_collection_success: Gauge::default(),
+            exporter_collection_errors_total: Family::<CollectionErrorLabel, Counter>::default(),
+            exporter_last_success_timestamp: Gauge::<f64, AtomicU64>::default(),
+            exporter_graphql_request_duration_seconds: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.01, 2.0, 12))
+            }),
+            exporter_graphql_response_size_bytes: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1024.0, 4.0, 10))
+            }),
+            exporter_graphql_errors_total: Family::<QueryLabel, Counter>::default(),
+            exporter_section_errors_total: Family::<SectionErrorLabel, Counter>::default(),
+            exporter_section_processing_duration_seconds: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.0001, 4.0, 10))
+            }),
             exporter_last_scrape_timestamp: Gauge::<f64, AtomicU64>::default(),
 
-            concurrency_metrics
+            instance: (options.instance_label.clone(), instance.to_owned()),
+            schedule_tick_grace: options.schedule_tick_grace as f64,
+            stuck_tick_timeout: options.stuck_tick_timeout as f64,
+            metric_ttl: options.metric_ttl.map(|ttl| ttl as f64),
+            concurrency_metrics: options.concurrency_metrics,
+            asset_metrics: options.asset_metrics,
+            partition_metrics: options.partition_refresh.is_some(),
+            auto_materialize_metrics: options.auto_materialize_metrics,
+            run_failure_metrics: options.run_failure_metrics,
+            event_log_metrics: options.event_log_metrics,
+            event_log_queue_size: options.event_log_queue_size,
+            event_log_retries: options.event_log_retries,
+            histograms: options.histograms
         }
     }
 
+    /// Processes a section of Dagit's response with the given function, timing how long it takes
+    pub(super) fn timed<T>(&mut self, section: &str, f: impl FnOnce(&mut Self) -> T) -> T {
+        let start = Instant::now();
+        let result = f(self);
+        let elapsed = start.elapsed().as_secs_f64();
+        self.exporter_section_processing_duration_seconds.get_or_create(&SectionLabel::new(section)).observe(elapsed);
+        result
+    }
+
+    /// Logs a section of Dagit's response which holds an error instead of data, counting it by the type of error
+    pub(super) fn section_error(&self, section: &str, error_type: &str, class_name: Option<&str>, message: &str) {
+        eprintln!(
+            "level=error {}={} section={section} error_type={error_type} class_name={} message={message:?}",
+            self.instance.0,
+            self.instance.1,
+            class_name.unwrap_or_default()
+        );
+        self.exporter_section_errors_total.get_or_create(&SectionErrorLabel::new(section, error_type)).inc();
+    }
+
     pub(super) fn registry(&self) -> Registry {
-        let mut registry = <Registry>::default();
+        let (name, value) = self.instance.clone();
+        let mut registry = Registry::with_labels(iter::once((Cow::Owned(name), Cow::Owned(value))));
         registry.register(
             "run",
             "The cumulative total number of runs since the exporter was started",
             self.run_total.clone()
         );
+        if self.run_failure_metrics {
+            registry.register(
+                "run_failures",
+                "The cumulative total number of run and step failures since the exporter was started, by Python error class",
+                self.run_failures_total.clone()
+            );
+        }
         registry.register_with_unit(
             "run_duration",
             "The total execution time of the latest Dagster runs",
@@ -93,6 +292,74 @@ impl Metrics {
             "The total number of runs triggered per instigator (schedule/sensor)",
             self.runs_by_instigation_total.clone()
         );
+        registry.register(
+            "instigation_ticks",
+            "The cumulative total number of finished ticks per instigator (schedule/sensor) since the exporter was started",
+            self.instigation_ticks_total.clone()
+        );
+        registry.register_with_unit(
+            "instigation_last_tick",
+            "The time of the latest tick per instigator (schedule/sensor)",
+            Unit::Seconds,
+            self.instigation_last_tick_seconds.clone()
+        );
+        registry.register_with_unit(
+            "instigation_last_successful_tick",
+            "The time of the latest successful tick per instigator (schedule/sensor)",
+            Unit::Seconds,
+            self.instigation_last_successful_tick_seconds.clone()
+        );
+        registry.register(
+            "instigation_last_tick_failed",
+            "The value of this metric is 1 if the instigator's (schedule/sensor) latest finished tick has failed",
+            self.instigation_last_tick_failed.clone()
+        );
+        registry.register_with_unit(
+            "schedule_last_expected_tick_timestamp",
+            "The time of the latest tick expected from the schedule's cron schedule",
+            Unit::Seconds,
+            self.schedule_last_expected_tick_timestamp_seconds.clone()
+        );
+        registry.register_with_unit(
+            "schedule_last_actual_tick_timestamp",
+            "The time of the latest tick actually recorded for the schedule",
+            Unit::Seconds,
+            self.schedule_last_actual_tick_timestamp_seconds.clone()
+        );
+        registry.register(
+            "schedule_missed_ticks",
+            "The number of ticks expected from the running schedule's cron schedule since its latest actual tick",
+            self.schedule_missed_ticks.clone()
+        );
+        registry.register(
+            "sensor_running",
+            "The value of this metric is 1 if the sensor is currently running and 0 if it is stopped",
+            self.sensor_running.clone()
+        );
+        registry.register_with_unit(
+            "sensor_next_tick_timestamp",
+            "The time at which the sensor is next expected to tick",
+            Unit::Seconds,
+            self.sensor_next_tick_timestamp_seconds.clone()
+        );
+        registry.register_with_unit(
+            "sensor_min_interval",
+            "The minimum time between the sensor's evaluations",
+            Unit::Seconds,
+            self.sensor_min_interval_seconds.clone()
+        );
+        registry.register("sensor_target_info", "The jobs targeted by each sensor", self.sensor_target_info.clone());
+        registry.register(
+            "runs_in_progress",
+            "The number of runs which are currently queued, starting, started or canceling",
+            self.runs_in_progress.clone()
+        );
+        registry.register_with_unit(
+            "run_in_progress_oldest_age",
+            "The time since the oldest in-progress run was enqueued (if queued) or started",
+            Unit::Seconds,
+            self.run_in_progress_oldest_age_seconds.clone()
+        );
         registry.register(
             "step",
             "The cumulative total number of steps since the exporter was started",
@@ -119,6 +386,96 @@ impl Metrics {
             Unit::Seconds,
             self.asset_materialization_timestamp.clone()
         );
+        registry.register(
+            "asset_metadata",
+            "The value of an allowlisted numeric metadata entry from an asset's latest materialization",
+            self.asset_metadata.clone()
+        );
+        if self.asset_metrics {
+            registry.register(
+                "asset_freshness_current_minutes_late",
+                "The number of minutes by which the asset currently violates its freshness policy",
+                self.asset_freshness_current_minutes_late.clone()
+            );
+            registry.register(
+                "asset_freshness_latest_materialization_minutes_late",
+                "The number of minutes by which the asset's latest materialization violated its freshness policy",
+                self.asset_freshness_latest_materialization_minutes_late.clone()
+            );
+            registry.register(
+                "asset_freshness_maximum_lag_minutes",
+                "The maximum number of minutes the asset may lag behind its upstream data under its freshness policy",
+                self.asset_freshness_maximum_lag_minutes.clone()
+            );
+            registry.register(
+                "asset_stale_status",
+                "The value of this metric is 1 for the asset's current stale status (MISSING, STALE or FRESH)",
+                self.asset_stale_status.clone()
+            );
+        }
+        if self.partition_metrics {
+            registry.register(
+                "asset_partitions",
+                "The number of the partitioned asset's partitions per state (materialized, failed, materializing, missing)",
+                self.asset_partitions.clone()
+            );
+            registry.register_with_unit(
+                "asset_latest_partition_end_timestamp",
+                "The end of the time-partitioned asset's newest materialized time window",
+                Unit::Seconds,
+                self.asset_latest_partition_end_timestamp_seconds.clone()
+            );
+        }
+        if self.auto_materialize_metrics {
+            registry.register(
+                "auto_materialize_paused",
+                "The value of this metric is 1 if the Dagster instance's auto-materialize daemon is paused",
+                self.auto_materialize_paused.clone()
+            );
+            registry.register(
+                "auto_materialize_evaluations",
+                "The cumulative total number of auto-materialize decisions (requested, skipped, discarded) per asset since the exporter was started",
+                self.auto_materialize_evaluations_total.clone()
+            );
+            registry.register_with_unit(
+                "auto_materialize_last_evaluation",
+                "The time of the auto-materialize daemon's latest evaluation of the asset",
+                Unit::Seconds,
+                self.auto_materialize_last_evaluation_seconds.clone()
+            );
+        }
+        registry.register(
+            "backfill",
+            "The cumulative total number of partition backfills which reached a terminal status since the exporter was started",
+            self.backfill_total.clone()
+        );
+        registry.register(
+            "backfill_partitions",
+            "The number of partitions targeted by the latest partition backfills",
+            self.backfill_partitions.clone()
+        );
+        registry.register(
+            "backfill_cancelable_partitions",
+            "The number of partitions which can still be canceled for the latest partition backfills",
+            self.backfill_cancelable_partitions.clone()
+        );
+        registry.register(
+            "backfill_partition_runs",
+            "The number of partitions per run status for the latest partition backfills",
+            self.backfill_partition_runs.clone()
+        );
+        registry.register_with_unit(
+            "backfill_age",
+            "The time since the latest partition backfills were launched",
+            Unit::Seconds,
+            self.backfill_age_seconds.clone()
+        );
+        registry.register_with_unit(
+            "backfill_duration",
+            "The total execution time of the latest partition backfills which have finished",
+            Unit::Seconds,
+            self.backfill_duration_seconds.clone()
+        );
         registry.register_with_unit(
             "daemon_last_heartbeat",
             "The last daemon heartbeat time reported to Dagit",
@@ -131,6 +488,56 @@ impl Metrics {
             Unit::Seconds,
             self.workspace_location_last_update_seconds.clone()
         );
+        registry.register(
+            "workspace_location_status",
+            "The value of this metric is 1 for the workspace location's current load status (LOADING or LOADED)",
+            self.workspace_location_status.clone()
+        );
+        registry.register(
+            "workspace_location_load_error",
+            "The value of this metric is 1 for each workspace location which failed to load, by the class of its error",
+            self.workspace_location_load_error.clone()
+        );
+        if self.histograms {
+            registry.register_with_unit(
+                "run_duration_histogram",
+                "The distribution of execution times of all Dagster runs seen by the exporter",
+                Unit::Seconds,
+                self.run_duration_histogram.clone()
+            );
+            registry.register_with_unit(
+                "run_queue_histogram",
+                "The distribution of queueing times of all Dagster runs seen by the exporter",
+                Unit::Seconds,
+                self.run_queue_histogram.clone()
+            );
+            registry.register_with_unit(
+                "step_duration_histogram",
+                "The distribution of execution times of all Dagster runs' steps seen by the exporter",
+                Unit::Seconds,
+                self.step_duration_histogram.clone()
+            );
+        }
+        if self.event_log_metrics {
+            registry.register_with_unit(
+                "step_worker_startup_histogram",
+                "The distribution of times between steps' workers starting and having started",
+                Unit::Seconds,
+                self.step_worker_startup_histogram.clone()
+            );
+            registry.register_with_unit(
+                "step_resource_init_histogram",
+                "The distribution of times taken by steps to initialize their resources",
+                Unit::Seconds,
+                self.step_resource_init_histogram.clone()
+            );
+            registry.register_with_unit(
+                "step_retry_delay_histogram",
+                "The distribution of times between steps being marked up for retry and restarting",
+                Unit::Seconds,
+                self.step_retry_delay_histogram.clone()
+            );
+        }
         if self.concurrency_metrics {
             registry.register(
                 "concurrency_slots",
@@ -153,16 +560,93 @@ impl Metrics {
                 self.concurrency_assigned_steps.clone()
             );
         }
-        registry.register(
-            "exporter_last_scrape_runs",
+
+        // The exporter's own metrics are kept apart from Dagster's
+        let exporter = registry.sub_registry_with_prefix("exporter");
+        exporter.register(
+            "last_scrape_runs",
             "The number of runs collected by the exporter's last query to Dagit",
             self.exporter_last_scrape_runs.clone()
         );
-        registry.register(
-            "exporter_last_scrape_timestamp",
+        exporter.register(
+            "duplicate_runs",
+            "The cumulative total number of already counted runs which were seen again and not counted twice",
+            self.exporter_duplicate_runs_total.clone()
+        );
+        exporter.register(
+            "collection_success",
+            "Whether the exporter's last collection of metrics from Dagit succeeded (1) or failed (0)",
+            self.exporter_collection_success.clone()
+        );
+        exporter.register(
+            "collection_errors",
+            "The cumulative total number of failed collections of metrics from Dagit, by kind of failure",
+            self.exporter_collection_errors_total.clone()
+        );
+        exporter.register_with_unit(
+            "last_success_timestamp",
+            "The timestamp of the exporter's last successful collection of metrics from Dagit",
+            Unit::Seconds,
+            self.exporter_last_success_timestamp.clone()
+        );
+        exporter.register(
+            "last_scrape_timestamp",
             "The timestamp of the exporter's last query to Dagit",
             self.exporter_last_scrape_timestamp.clone()
         );
+        exporter.register_with_unit(
+            "graphql_request_duration",
+            "The time taken by the exporter's queries to Dagit, including reading the response",
+            Unit::Seconds,
+            self.exporter_graphql_request_duration_seconds.clone()
+        );
+        exporter.register_with_unit(
+            "graphql_response_size",
+            "The size of the responses to the exporter's queries to Dagit",
+            Unit::Bytes,
+            self.exporter_graphql_response_size_bytes.clone()
+        );
+        exporter.register(
+            "graphql_errors",
+            "The cumulative total number of errors reported in the responses to the exporter's queries to Dagit",
+            self.exporter_graphql_errors_total.clone()
+        );
+        exporter.register_with_unit(
+            "section_processing_duration",
+            "The time taken by the exporter to process each section of Dagit's responses into metrics",
+            Unit::Seconds,
+            self.exporter_section_processing_duration_seconds.clone()
+        );
+        exporter.register(
+            "section_errors",
+            "The cumulative total number of sections of Dagit's responses which held an error instead of data",
+            self.exporter_section_errors_total.clone()
+        );
         registry
     }
 }
+
+/// Keys whose label names collide after sanitising are only exported once, under the first of them
+fn run_tags(keys: &[String]) -> Vec<(String, String)> {
+    let mut names = HashMap::new();
+    keys.iter()
+        .map(|key| (key.clone(), tag_label_name(key)))
+        .filter(|(key, name)| match names.get(name) {
+            Some(first) => {
+                eprintln!(
+                    "Ignoring the run tag {key:?}, whose label {name} is already exported for the run tag {first:?}"
+                );
+                false
+            }
+            None => {
+                names.insert(name.clone(), key.clone());
+                true
+            }
+        })
+        .collect()
+}
+
+/// The current unix time in fractional seconds
+pub(super) fn now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock time").as_secs_f64()
+}
@@ -0,0 +1,282 @@
+use clap::{Args, Parser};
+
+use std::path::PathBuf;
+
+const RUN_DURATION_BUCKETS: &str = "1,5,15,30,60,120,300,600,1800,3600,7200,14400";
+const RUN_QUEUE_BUCKETS: &str = "0.5,1,2,5,10,30,60,120,300,600";
+const STEP_DURATION_BUCKETS: &str = "0.1,0.5,1,5,15,30,60,120,300,600,1800,3600";
+
+/// Settings controlling which metrics the exporter collects and how they are shaped
+#[derive(Args, Clone, Debug)]
+pub struct Options {
+    /// Query the Dagit GraphQL API every refresh period in the background, rather than on the first scrape after
+    /// the refresh period has passed, so that scrapes only encode the last collected metrics
+    #[arg(long, default_value_t = false)]
+    pub background_collection: bool,
+
+    /// How long (e.g. `3s`) to wait for a connection to the Dagit GraphQL API
+    #[arg(long, value_parser = parse_duration, default_value = "3s")]
+    pub connect_timeout: u64,
+
+    /// How long (e.g. `8s`) to wait for each query to the Dagit GraphQL API to be answered. Should be shorter than
+    /// Prometheus' scrape timeout
+    #[arg(long, value_parser = parse_duration, default_value = "8s")]
+    pub request_timeout: u64,
+
+    /// How long (e.g. `8s`) a scrape waits for each deployment's collection, or for one already in flight, before it
+    /// is served the last collected metrics instead. Should be shorter than Prometheus' scrape timeout
+    #[arg(long, value_parser = parse_duration, default_value = "8s")]
+    pub scrape_timeout: u64,
+
+    /// Export the freshness and stale status of every asset. Dagster computes stale statuses asset by asset, which
+    /// is expensive for large asset graphs
+    #[arg(long, default_value_t = false)]
+    pub asset_metrics: bool,
+
+    /// Export metrics for the Dagster instance's tagged concurrency slots
+    #[arg(short, long, default_value_t = false)]
+    pub concurrency_metrics: bool,
+
+    /// Export run/step durations and run queueing times as histograms alongside the last-value gauges
+    #[arg(long, default_value_t = false)]
+    pub histograms: bool,
+
+    // The buckets are spelled `::std::vec::Vec` so that clap parses each flag as a single value, not a repeated one
+    /// Comma-separated bucket boundaries (in seconds) for the run duration histogram
+    #[arg(long, value_parser = parse_buckets, default_value = RUN_DURATION_BUCKETS)]
+    pub run_duration_buckets: ::std::vec::Vec<f64>,
+
+    /// Comma-separated bucket boundaries (in seconds) for the run queueing time histogram
+    #[arg(long, value_parser = parse_buckets, default_value = RUN_QUEUE_BUCKETS)]
+    pub run_queue_buckets: ::std::vec::Vec<f64>,
+
+    /// Comma-separated bucket boundaries (in seconds) for the step duration histogram
+    #[arg(long, value_parser = parse_buckets, default_value = STEP_DURATION_BUCKETS)]
+    pub step_duration_buckets: ::std::vec::Vec<f64>,
+
+    /// How old (e.g. `10m`) the last collected metrics may get while collecting from the Dagit GraphQL API keeps
+    /// failing, before scrapes fail too. By default, the last collected metrics are served however old they are
+    #[arg(long, value_parser = parse_duration)]
+    pub max_staleness: Option<u64>,
+
+    /// How far back (e.g. `90m`, `24h` or `7d`) to replay terminal runs from when the exporter starts, so that
+    /// run metrics don't start out empty after every restart
+    #[arg(long, value_parser = parse_duration)]
+    pub lookback: Option<u64>,
+
+    /// How long (e.g. `7d`) to keep exporting the metrics of a job's runs, steps, expectations and materializations
+    /// after they were last seen. By default they are exported for as long as the exporter runs
+    #[arg(long, value_parser = parse_duration)]
+    pub metric_ttl: Option<u64>,
+
+    /// A file to save the exporter's run/step metrics and progress to, and to restore them from on startup, so that
+    /// they survive restarts
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+
+    /// How many seconds between saves of the state file, which is also saved on shutdown
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub state_interval: u64,
+
+    /// How many terminal runs to fetch from the Dagit GraphQL API per query
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(i64).range(1..))]
+    pub runs_page_size: i64,
+
+    /// How many of the most recently counted run IDs to remember, so runs updated again after finishing aren't
+    /// counted twice
+    #[arg(long, default_value_t = 10000)]
+    pub seen_runs_capacity: usize,
+
+    /// How many seconds to remember a counted run ID for, since it was last seen
+    #[arg(long, default_value_t = 86400)]
+    pub seen_runs_max_age: u64,
+
+    /// How many of the most recent partition backfills to export metrics for. Backfill metrics aren't collected if
+    /// this is 0
+    #[arg(long, default_value_t = 25)]
+    pub backfill_limit: i64,
+
+    /// How many seconds a schedule's tick may lag behind its cron schedule before it is counted as missed
+    #[arg(long, default_value_t = 60)]
+    pub schedule_tick_grace: u64,
+
+    /// How long (e.g. `1h`) a schedule or sensor tick may stay in-flight before it is taken to have been abandoned
+    /// (e.g. by a daemon which died mid-tick), so that the ticks which finished after it are still counted
+    #[arg(long, value_parser = parse_duration, default_value = "1h")]
+    pub stuck_tick_timeout: u64,
+
+    /// How many seconds the exporter should serve old partitioned asset metrics before re-querying the Dagit
+    /// GraphQL API. These are expensive to compute for Dagster, so they are not collected unless this is set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub partition_refresh: Option<u64>,
+
+    /// The name of the label added to every metric, holding the name of the Dagster deployment it was collected from
+    #[arg(long, default_value = "instance")]
+    pub instance_label: String,
+
+    /// Comma-separated labels of numeric asset materialization metadata entries to export as metrics
+    #[arg(long, value_delimiter = ',')]
+    pub metadata_labels: Vec<String>,
+
+    /// Comma-separated run tag keys to add as labels to run, step, materialization and expectation metrics.
+    /// Each is exported as a label named after the key prefixed with `tag_`, with invalid characters replaced by `_`
+    #[arg(long, value_delimiter = ',')]
+    pub run_tags: Vec<String>,
+
+    /// Export the auto-materialize daemon's evaluations of each asset with an auto-materialize policy.
+    /// This costs one extra query to the Dagit GraphQL API per such asset
+    #[arg(long, default_value_t = false)]
+    pub auto_materialize_metrics: bool,
+
+    /// How many seconds the exporter should serve old auto-materialize evaluation metrics before re-querying the
+    /// Dagit GraphQL API for them
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub auto_materialize_refresh: u64,
+
+    /// Page through the event logs of failed runs to count run and step failures by their Python error class. This
+    /// costs at least one extra query to the Dagit GraphQL API per failed run
+    #[arg(long, default_value_t = false)]
+    pub run_failure_metrics: bool,
+
+    /// Page through the event logs of completed runs to export step worker startup, resource initialization and
+    /// retry delay histograms. This costs at least one extra query to the Dagit GraphQL API per run
+    #[arg(long, default_value_t = false)]
+    pub event_log_metrics: bool,
+
+    /// How many completed runs' event logs to page through per collection at most, oldest first. The rest are left
+    /// for later collections
+    #[arg(long, default_value_t = 20)]
+    pub event_log_runs_per_collection: usize,
+
+    /// How many completed runs may wait for their event logs to be paged through at most. Beyond that, the runs
+    /// which have waited longest are dropped without being mined
+    #[arg(long, default_value_t = 1000)]
+    pub event_log_queue_size: usize,
+
+    /// How many times to retry querying a run's event log before giving up on the run
+    #[arg(long, default_value_t = 3)]
+    pub event_log_retries: u32
+}
+
+/// The options as they are when no flags are passed, taken from the flags' own defaults
+impl Default for Options {
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            options: Options
+        }
+        Defaults::parse_from(["dagster-prom-exporter"]).options
+    }
+}
+
+/// Parses comma-separated histogram bucket boundaries, which must be finite and strictly increasing for the
+/// histogram's `le` buckets to be valid
+fn parse_buckets(s: &str) -> Result<Vec<f64>, String> {
+    let buckets = s
+        .split(',')
+        .map(|b| b.trim().parse::<f64>().map_err(|e| format!("invalid bucket boundary '{b}': {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(b) = buckets.iter().find(|b| !b.is_finite()) {
+        return Err(format!("bucket boundary {b} isn't finite"));
+    }
+    if let Some(w) = buckets.windows(2).find(|w| w[0] >= w[1]) {
+        return Err(format!("bucket boundaries must be strictly increasing, but {} is followed by {}", w[0], w[1]));
+    }
+    Ok(buckets)
+}
+
+/// Parses a duration made up of numbers suffixed by a unit (`s`, `m`, `h`, `d` or `w`) into seconds, such as
+/// `1h30m`. A trailing number without a unit is taken as seconds.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    const TOO_LONG: &str = "duration is too long";
+    let mut secs: u64 = 0;
+    let mut digits = String::new();
+
+    if s.trim().is_empty() {
+        return Err("missing a duration".to_owned());
+    }
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(format!("unknown duration unit '{c}'"))
+        };
+        if digits.is_empty() {
+            return Err(format!("missing a number before '{c}'"));
+        }
+        let n: u64 = digits.parse().map_err(|_| TOO_LONG)?;
+        secs = n.checked_mul(unit).and_then(|n| secs.checked_add(n)).ok_or(TOO_LONG)?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        let n: u64 = digits.parse().map_err(|_| TOO_LONG)?;
+        secs = secs.checked_add(n).ok_or(TOO_LONG)?;
+    }
+    Ok(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("8s"), Ok(8));
+        assert_eq!(parse_duration("1h30m"), Ok(5400));
+        assert_eq!(parse_duration(" 7d "), Ok(7 * 86400));
+        assert_eq!(parse_duration("1w1d1h1m1"), Ok(8 * 86400 + 3661));
+        assert_eq!(parse_duration("0s"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("  ").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1h m").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert!(parse_duration("40000000000000w").is_err());
+        assert!(parse_duration(&format!("{}s1", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(parse_buckets("1"), Ok(vec![1.0]));
+        assert_eq!(parse_buckets("0.5, 1,2.5"), Ok(vec![0.5, 1.0, 2.5]));
+        assert_eq!(parse_buckets("-1,0,1e3"), Ok(vec![-1.0, 0.0, 1000.0]));
+        for default in [RUN_DURATION_BUCKETS, RUN_QUEUE_BUCKETS, STEP_DURATION_BUCKETS] {
+            assert!(parse_buckets(default).is_ok());
+        }
+    }
+
+    #[test]
+    fn invalid_buckets() {
+        assert!(parse_buckets("").is_err());
+        assert!(parse_buckets("1,,2").is_err());
+        assert!(parse_buckets("1,x").is_err());
+        assert!(parse_buckets("1,inf").is_err());
+        assert!(parse_buckets("NaN").is_err());
+        assert!(parse_buckets("1,1").is_err());
+        assert!(parse_buckets("2,1").is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        let options = Options::default();
+        assert_eq!(options.stuck_tick_timeout, 3600);
+        assert_eq!(options.run_duration_buckets[0], 1.0);
+        assert_eq!(options.instance_label, "instance");
+    }
+}
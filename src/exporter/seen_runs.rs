@@ -0,0 +1,127 @@
+use std::collections::{HashMap, VecDeque};
+
+/// The IDs of recently counted runs, bounded in number and age. Once a run is forgotten it would be counted again if
+/// it were seen again, so the bounds should comfortably cover how long runs keep being updated after they finish.
+pub(super) struct SeenRuns {
+    capacity: usize,
+    max_age: f64,
+    /// When each run was last seen
+    seen: HashMap<String, f64>,
+    /// Every sighting in order, including ones outdated by a later sighting of the same run
+    order: VecDeque<(String, f64)>
+}
+
+impl SeenRuns {
+    pub(super) fn new(capacity: usize, max_age: f64) -> Self {
+        Self { capacity, max_age, seen: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Records a sighting of the run at the given unix seconds, returning whether it hadn't been seen before
+    pub(super) fn insert(&mut self, run_id: String, now: f64) -> bool {
+        self.evict(now);
+        let new = self.seen.insert(run_id.clone(), now).is_none();
+        self.order.push_back((run_id, now));
+
+        if new && self.seen.len() > self.capacity {
+            self.evict_least_recent();
+        }
+        // Re-seen runs leave outdated sightings behind, which are dropped before they can pile up
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let seen = &self.seen;
+            self.order.retain(|(id, t)| seen.get(id) == Some(t));
+        }
+        new
+    }
+
+    /// Every remembered run, from the least to the most recently seen
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.order.iter().filter(|(id, t)| self.seen.get(id) == Some(t)).map(|(id, t)| (id, *t))
+    }
+
+    fn evict(&mut self, now: f64) {
+        while let Some((id, t)) = self.order.front() {
+            if self.seen.get(id) == Some(t) {
+                if now - t <= self.max_age {
+                    break;
+                }
+                self.seen.remove(id);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    fn evict_least_recent(&mut self) {
+        while let Some((id, t)) = self.order.pop_front() {
+            if self.seen.get(&id) == Some(&t) {
+                self.seen.remove(&id);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(seen: &SeenRuns) -> Vec<(&str, f64)> {
+        seen.iter().map(|(id, t)| (id.as_str(), t)).collect()
+    }
+
+    #[test]
+    fn re_sighting() {
+        let mut seen = SeenRuns::new(10, 100.0);
+        assert!(seen.insert("a".to_owned(), 0.0));
+        assert!(seen.insert("b".to_owned(), 1.0));
+        assert!(!seen.insert("a".to_owned(), 2.0));
+        assert_eq!(runs(&seen), [("b", 1.0), ("a", 2.0)]);
+    }
+
+    #[test]
+    fn eviction_at_capacity() {
+        let mut seen = SeenRuns::new(2, 100.0);
+        seen.insert("a".to_owned(), 0.0);
+        seen.insert("b".to_owned(), 1.0);
+        seen.insert("c".to_owned(), 2.0);
+        assert_eq!(runs(&seen), [("b", 1.0), ("c", 2.0)]);
+        assert!(seen.insert("a".to_owned(), 3.0));
+
+        // The least recently seen run is evicted, rather than the one seen first
+        let mut seen = SeenRuns::new(2, 100.0);
+        seen.insert("a".to_owned(), 0.0);
+        seen.insert("b".to_owned(), 1.0);
+        seen.insert("a".to_owned(), 2.0);
+        seen.insert("c".to_owned(), 3.0);
+        assert_eq!(runs(&seen), [("a", 2.0), ("c", 3.0)]);
+    }
+
+    #[test]
+    fn expiry_by_age() {
+        let mut seen = SeenRuns::new(10, 5.0);
+        seen.insert("a".to_owned(), 0.0);
+        seen.insert("b".to_owned(), 3.0);
+        seen.insert("c".to_owned(), 7.0);
+        assert_eq!(runs(&seen), [("b", 3.0), ("c", 7.0)]);
+        assert!(seen.insert("a".to_owned(), 8.0));
+
+        // Seeing a run again keeps it from expiring
+        seen.insert("b".to_owned(), 8.0);
+        seen.insert("d".to_owned(), 13.0);
+        assert_eq!(runs(&seen), [("a", 8.0), ("b", 8.0), ("d", 13.0)]);
+    }
+
+    #[test]
+    fn iter_after_compaction() {
+        let mut seen = SeenRuns::new(2, 100.0);
+        seen.insert("a".to_owned(), 0.0);
+        seen.insert("b".to_owned(), 1.0);
+        for t in 2..10 {
+            seen.insert("a".to_owned(), f64::from(t));
+        }
+        assert!(seen.order.len() <= 4);
+        assert_eq!(runs(&seen), [("b", 1.0), ("a", 9.0)]);
+
+        seen.insert("c".to_owned(), 10.0);
+        assert_eq!(runs(&seen), [("a", 9.0), ("c", 10.0)]);
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use super::event_log::EventLogRun;
+use super::labels::{
+    AssetLabel, AutoMaterializeLabel, BackfillLabel, ExpectationLabel, InstigationLabel, InstigationTickLabel,
+    MaterializationLabel, MetadataLabel, RunFailureLabel, RunLabel, StepLabel
+};
+use super::metrics::Metrics;
+
+/// Bumped whenever the shape of the state changes, so that state saved by an incompatible version is never loaded
+const STATE_VERSION: u32 = 4;
+
+/// What the exporter keeps across restarts: where it left off querying runs, ticks, backfills and auto-materialize
+/// evaluations, and the counters and run/step metrics which would otherwise start over from zero (or go missing for
+/// rarely-run jobs). Each metric is kept along with when it was last updated, so that `--metric-ttl` still expires it
+/// across restarts. Histograms are not kept.
+#[derive(Serialize, Deserialize)]
+pub(super) struct State {
+    version: u32,
+    /// The run tag keys the run labels were saved with, which must match the ones they're restored with
+    run_tags: Vec<String>,
+    cursor: f64,
+    runs_page_cursor: Option<String>,
+    runs_page_update_time: f64,
+    seen_runs: Vec<(String, f64)>,
+    /// The runs still queued for mining, which wouldn't be queued again since they're among the seen runs
+    event_log_runs: Vec<(String, EventLogRun)>,
+
+    run_total: Vec<(RunLabel, u64, f64)>,
+    run_failures_total: Vec<(RunFailureLabel, u64, f64)>,
+    run_duration_seconds: Vec<(RunLabel, f64, f64)>,
+    run_queue_seconds: Vec<(RunLabel, f64, f64)>,
+    step_total: Vec<(StepLabel, u64, f64)>,
+    step_duration_seconds: Vec<(StepLabel, f64, f64)>,
+    step_attempts: Vec<(StepLabel, i64, f64)>,
+    expectation_failure: Vec<(ExpectationLabel, i64, f64)>,
+    asset_materialization_timestamp: Vec<(MaterializationLabel, f64, f64)>,
+    asset_metadata: Vec<(MetadataLabel, f64, f64)>,
+
+    instigation_ticks_total: Vec<(InstigationTickLabel, u64, f64)>,
+    tick_cursors: Vec<(InstigationLabel, f64)>,
+    backfill_total: Vec<(BackfillLabel, u64, f64)>,
+    backfills_seen: Vec<String>,
+    auto_materialize_evaluations_total: Vec<(AutoMaterializeLabel, u64, f64)>,
+    auto_materialize_cursors: Vec<(AssetLabel, i64)>,
+    exporter_duplicate_runs_total: u64
+}
+
+impl State {
+    /// Returns no state if the file doesn't exist yet
+    pub(super) fn load(path: &Path) -> Result<Option<Self>> {
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+
+        // Check the version before anything else, since an incompatible state may not deserialize at all
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32
+        }
+        let Version { version } = serde_json::from_slice(&bytes)?;
+        if version != STATE_VERSION {
+            return Err(anyhow!("state version {version} is incompatible with version {STATE_VERSION}"));
+        }
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Writes the state to a temporary file first and then moves it into place, so that a crash while saving never
+    /// leaves a truncated state behind
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Metrics {
+    pub(super) fn state(&self) -> State {
+        State {
+            version: STATE_VERSION,
+            run_tags: self.run_tags.iter().map(|(key, _)| key.clone()).collect(),
+            cursor: self.cursor,
+            runs_page_cursor: self.runs_page_cursor.clone(),
+            runs_page_update_time: self.runs_page_update_time,
+            seen_runs: self.seen_runs.iter().map(|(id, t)| (id.clone(), t)).collect(),
+            event_log_runs: self.event_log_runs.iter().map(|(id, run)| (id.clone(), run.clone())).collect(),
+
+            run_total: self.run_total.snapshot(|c| c.get()),
+            run_failures_total: self.run_failures_total.snapshot(|c| c.get()),
+            run_duration_seconds: self.run_duration_seconds.snapshot(|g| g.get()),
+            run_queue_seconds: self.run_queue_seconds.snapshot(|g| g.get()),
+            step_total: self.step_total.snapshot(|c| c.get()),
+            step_duration_seconds: self.step_duration_seconds.snapshot(|g| g.get()),
+            step_attempts: self.step_attempts.snapshot(|g| g.get()),
+            expectation_failure: self.expectation_failure.snapshot(|g| g.get()),
+            asset_materialization_timestamp: self.asset_materialization_timestamp.snapshot(|g| g.get()),
+            asset_metadata: self.asset_metadata.snapshot(|g| g.get()),
+
+            instigation_ticks_total: self.instigation_ticks_total.snapshot(|c| c.get()),
+            tick_cursors: self.tick_cursors.iter().map(|(l, t)| (l.clone(), *t)).collect(),
+            backfill_total: self.backfill_total.snapshot(|c| c.get()),
+            backfills_seen: self.backfills_seen.iter().cloned().collect(),
+            auto_materialize_evaluations_total: self.auto_materialize_evaluations_total.snapshot(|c| c.get()),
+            auto_materialize_cursors: self.auto_materialize_cursors.iter().map(|(l, c)| (l.clone(), *c)).collect(),
+            exporter_duplicate_runs_total: self.exporter_duplicate_runs_total.get()
+        }
+    }
+
+    /// Fails without restoring anything if the state's run labels were saved with other run tags
+    pub(super) fn restore(&mut self, state: State) -> Result<()> {
+        let run_tags: Vec<_> = self.run_tags.iter().map(|(key, _)| key.clone()).collect();
+        if state.run_tags != run_tags {
+            return Err(anyhow!("it was saved with the run tags {:?} rather than {run_tags:?}", state.run_tags));
+        }
+
+        self.cursor = state.cursor;
+        self.runs_page_cursor = state.runs_page_cursor;
+        self.runs_page_update_time = state.runs_page_update_time;
+        for (run_id, seen) in state.seen_runs {
+            self.seen_runs.insert(run_id, seen);
+        }
+        self.event_log_runs.extend(state.event_log_runs);
+
+        self.run_total.restore(state.run_total, |c, v| c.inc_by(v));
+        self.run_failures_total.restore(state.run_failures_total, |c, v| c.inc_by(v));
+        self.run_duration_seconds.restore(state.run_duration_seconds, |g, v| g.set(v));
+        self.run_queue_seconds.restore(state.run_queue_seconds, |g, v| g.set(v));
+        self.step_total.restore(state.step_total, |c, v| c.inc_by(v));
+        self.step_duration_seconds.restore(state.step_duration_seconds, |g, v| g.set(v));
+        self.step_attempts.restore(state.step_attempts, |g, v| g.set(v));
+        self.expectation_failure.restore(state.expectation_failure, |g, v| g.set(v));
+        self.asset_materialization_timestamp.restore(state.asset_materialization_timestamp, |g, v| g.set(v));
+        self.asset_metadata.restore(state.asset_metadata, |g, v| g.set(v));
+
+        self.instigation_ticks_total.restore(state.instigation_ticks_total, |c, v| c.inc_by(v));
+        self.tick_cursors.extend(state.tick_cursors);
+        self.backfill_total.restore(state.backfill_total, |c, v| c.inc_by(v));
+        self.backfills_seen.extend(state.backfills_seen);
+        self.auto_materialize_evaluations_total.restore(state.auto_materialize_evaluations_total, |c, v| c.inc_by(v));
+        self.auto_materialize_cursors.extend(state.auto_materialize_cursors);
+        self.exporter_duplicate_runs_total.inc_by(state.exporter_duplicate_runs_total);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    use super::super::labels::CommonLabel;
+
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// A state file of the test's own, which is removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("dagster-exporter-{}-{name}.json", std::process::id())))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn run_label() -> RunLabel {
+        RunLabel::new("SUCCESS".to_owned(), "default".to_owned(), CommonLabel::new(None, "job".to_owned(), Vec::new()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = TempFile::new("round-trip");
+        let mut saved = Metrics::new("test", &Options::default());
+        saved.run_total.get_or_create(&run_label()).inc_by(3);
+        saved.exporter_duplicate_runs_total.inc_by(2);
+        saved.cursor = 1000.0;
+        saved.seen_runs.insert("a".to_owned(), 1000.0);
+        saved.event_log_runs.insert("a".to_owned(), EventLogRun::new(run_label(), HashMap::new(), true, false));
+        saved.state().save(&file.0).unwrap();
+
+        let mut restored = Metrics::new("test", &Options::default());
+        restored.restore(State::load(&file.0).unwrap().unwrap()).unwrap();
+        assert_eq!(restored.cursor, 1000.0);
+        assert_eq!(restored.seen_runs.iter().map(|(id, t)| (id.as_str(), t)).collect::<Vec<_>>(), [("a", 1000.0)]);
+        assert!(restored.event_log_runs.contains_key("a"));
+        assert_eq!(restored.exporter_duplicate_runs_total.get(), 2);
+        // Metrics keep the times they were last updated at, give or take the precision of JSON floats
+        let [(label, value, updated)] = &restored.run_total.snapshot(|c| c.get())[..] else { panic!() };
+        let [(saved_label, 3, saved_updated)] = &saved.run_total.snapshot(|c| c.get())[..] else { panic!() };
+        assert_eq!((label, *value), (saved_label, 3));
+        assert!((updated - saved_updated).abs() < 1e-3);
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(State::load(&TempFile::new("missing").0).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_version() {
+        let file = TempFile::new("other-version");
+        fs::write(&file.0, format!(r#"{{"version":{}}}"#, STATE_VERSION - 1)).unwrap();
+        let e = State::load(&file.0).err().unwrap();
+        assert!(e.to_string().contains("incompatible"), "{e}");
+    }
+
+    #[test]
+    fn other_run_tags() {
+        let mut saved = Metrics::new("test", &Options::default());
+        saved.cursor = 1000.0;
+        let state = saved.state();
+
+        let options = Options { run_tags: vec!["team".to_owned()], ..Options::default() };
+        let mut restored = Metrics::new("test", &options);
+        assert!(restored.restore(state).is_err());
+        assert_ne!(restored.cursor, 1000.0);
+    }
+}
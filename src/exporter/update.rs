@@ -1,53 +1,108 @@
+use super::asset_partition_query::{
+    AssetPartitionQueryAssetNodes, AssetPartitionQueryAssetNodesAssetPartitionStatuses, PartitionRangeStatus
+};
+use super::asset_query::{AssetQueryAssetNodes, StaleStatus};
+use super::auto_materialize_query::AutoMaterializeQueryAutoMaterializeAssetEvaluationsOrError;
+use super::backfill_query::{BackfillQueryPartitionBackfillsOrError, BulkActionStatus};
+use super::cron_schedule::CronSchedule;
+use super::event_log::EventLogRun;
+use std::collections::HashMap;
+
 #[allow(clippy::wildcard_imports)]
 use super::dagit_query::*;
-use super::labels::{CommonLabel, DaemonStatusLabel, InstigationLabel, RunLabel, StepLabel, WorkspaceLocationLabel};
-use super::metrics::Metrics;
+use super::labels::{
+    AssetLabel, BackfillLabel, CommonLabel, DaemonStatusLabel, InstigationLabel, MaterializationLabel, RunLabel,
+    SensorLabel, StepLabel, WorkspaceLocationErrorLabel, WorkspaceLocationLabel, WorkspaceLocationStateLabel
+};
+use super::metrics::{now, Metrics};
+use super::runs_query::{
+    self, RunsQueryRunsOrError, RunsQueryRunsOrErrorOnRunsResultsAssetMaterializationsMetadataEntries
+};
 
 impl Metrics {
-    pub(super) fn set_run_metrics(&mut self, runs: DagitQueryRunsOrError) {
-        use DagitQueryRunsOrError::Runs;
-        use DagitQueryRunsOrErrorOnRunsResultsStats::RunStatsSnapshot;
+    /// Returns whether there are more pages of runs left to process
+    pub(super) fn set_run_metrics(&mut self, runs: RunsQueryRunsOrError, page_size: i64) -> bool {
+        use runs_query::RunsQueryRunsOrErrorOnRunsResultsStats::RunStatsSnapshot;
+        use RunsQueryRunsOrError::{InvalidPipelineRunsFilterError, PythonError, Runs};
 
-        let Runs(r) = runs else { return };
+        let r = match runs {
+            Runs(r) => r,
+            PythonError(e) => {
+                self.section_error("runs", "PythonError", e.class_name.as_deref(), &e.message);
+                return false;
+            }
+            InvalidPipelineRunsFilterError(e) => {
+                self.section_error("runs", "InvalidPipelineRunsFilterError", None, &e.message);
+                return false;
+            }
+        };
 
         if let Some(i) = r.count {
             self.exporter_last_scrape_runs.set(i);
         }
 
+        let has_more = r.results.len() as i64 >= page_size;
+        let mut last_run_id = None;
+
         for run in r.results {
             if let Some(u) = run.update_time {
-                if self.cursor < u {
-                    self.exporter_last_scrape_timestamp.set(u);
-                    self.cursor = u;
+                if self.runs_page_update_time < u {
+                    self.runs_page_update_time = u;
                 }
             }
+            last_run_id = Some(run.run_id.clone());
 
             let label = RunLabel::new(
                 format!("{:?}", run.status),
                 run.mode,
-                CommonLabel::new(run.repository_origin, run.pipeline_name)
+                CommonLabel::new(
+                    run.repository_origin.map(Into::into),
+                    run.pipeline_name,
+                    self.tag_labels(run.tags.into_iter().map(|t| (t.key, t.value)))
+                )
             );
             self.clear_old_run_states(&label);
 
-            self.run_total.get_or_create(&label).inc();
+            // A run is seen again whenever it is updated after reaching a terminal state, but must only be counted once
+            let new = self.seen_runs.insert(run.run_id.clone(), now());
+            if new {
+                self.run_total.get_or_create(&label).inc();
+            } else {
+                self.exporter_duplicate_runs_total.inc();
+            }
 
             if let (Some(start), Some(end)) = (run.start_time, run.end_time) {
                 self.run_duration_seconds.get_or_create(&label).set(end - start);
+                if self.histograms && new {
+                    self.run_duration_histogram.get_or_create(&label).observe(end - start);
+                }
             }
             if let RunStatsSnapshot(stats) = run.stats {
                 if let (Some(start), Some(end)) = (stats.enqueued_time, stats.launch_time) {
                     self.run_queue_seconds.get_or_create(&label).set(end - start);
+                    if self.histograms && new {
+                        self.run_queue_histogram.get_or_create(&label).observe(end - start);
+                    }
                 }
             }
 
+            let mut steps = HashMap::new();
             for step in run.step_stats {
-                let label = label.step_label(step.step_key, step.status);
-                self.step_total.get_or_create(&label).inc();
+                let label = label.step_label(step.step_key.clone(), step.status);
+                if self.event_log_metrics {
+                    steps.insert(step.step_key, label.clone());
+                }
+                if new {
+                    self.step_total.get_or_create(&label).inc();
+                }
                 self.clear_old_step_states(&label);
 
                 self.step_attempts.get_or_create(&label).set(step.attempts.len() as i64);
                 if let (Some(start), Some(end)) = (step.start_time, step.end_time) {
                     self.step_duration_seconds.get_or_create(&label).set(end - start);
+                    if self.histograms && new {
+                        self.step_duration_histogram.get_or_create(&label).observe(end - start);
+                    }
                 }
                 for expectation in step.expectation_results {
                     let label = label.expectation_label(expectation.label);
@@ -55,34 +110,176 @@ impl Metrics {
                 }
             }
 
+            // Failed runs are mined for their errors, all runs for step latencies
+            let errors = self.run_failure_metrics && matches!(run.status, runs_query::RunStatus::FAILURE);
+            if new && (self.event_log_metrics || errors) {
+                let run_log = EventLogRun::new(label.clone(), steps, self.event_log_metrics, errors);
+                self.queue_event_log_run(run.run_id, run_log);
+            }
+
             for asset in run.asset_materializations {
                 if let (Some(k), Ok(i)) = (asset.asset_key, asset.timestamp.parse::<f64>()) {
                     let label = label.asset_label(asset.step_key, &k, asset.partition);
                     self.asset_materialization_timestamp.get_or_create(&label).set(i);
+                    self.set_metadata_metrics(&label, asset.metadata_entries);
                 }
             }
         }
+
+        if has_more {
+            self.runs_page_cursor = last_run_id;
+        } else {
+            self.runs_page_cursor = None;
+            self.cursor = self.runs_page_update_time;
+        }
+        has_more
+    }
+
+    /// Every allowlisted tag gets a label, left empty when the run doesn't carry it, so that all of a metric's
+    /// series share the same label names
+    fn tag_labels(&self, tags: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+        let mut tags: HashMap<String, String> = tags.into_iter().collect();
+        self.run_tags.iter().map(|(key, name)| (name.clone(), tags.remove(key).unwrap_or_default())).collect()
+    }
+
+    fn set_metadata_metrics(
+        &self, label: &MaterializationLabel,
+        entries: Vec<RunsQueryRunsOrErrorOnRunsResultsAssetMaterializationsMetadataEntries>
+    ) {
+        use runs_query::RunsQueryRunsOrErrorOnRunsResultsAssetMaterializationsMetadataEntriesOn::{
+            FloatMetadataEntry, IntMetadataEntry
+        };
+
+        for entry in entries.into_iter().filter(|e| self.metadata_labels.contains(&e.label)) {
+            let value = match entry.on {
+                FloatMetadataEntry(m) => m.float_value,
+                // Integers too large for GraphQL's 32-bit Int are only available through their string representation
+                IntMetadataEntry(m) => m.int_value.map(|i| i as f64).or_else(|| m.int_repr.parse().ok()),
+                _ => None
+            };
+            if let Some(v) = value {
+                self.asset_metadata.get_or_create(&label.metadata_label(entry.label)).set(v);
+            }
+        }
     }
 
-    pub(super) fn set_workspace_metrics(&self, workspaces: DagitQueryWorkspaceOrError) {
-        use DagitQueryWorkspaceOrError::Workspace;
-        use DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntriesLocationOrLoadError::RepositoryLocation;
+    pub(super) fn set_in_progress_run_metrics(&self, runs: DagitQueryInProgressRuns) {
+        use DagitQueryInProgressRuns::{InvalidPipelineRunsFilterError, PythonError, Runs};
+        use DagitQueryInProgressRunsOnRunsResultsStats::RunStatsSnapshot;
+
+        let r = match runs {
+            Runs(r) => r,
+            PythonError(e) => {
+                self.section_error("in_progress_runs", "PythonError", e.class_name.as_deref(), &e.message);
+                return;
+            }
+            InvalidPipelineRunsFilterError(e) => {
+                self.section_error("in_progress_runs", "InvalidPipelineRunsFilterError", None, &e.message);
+                return;
+            }
+        };
+
+        // Unlike terminal runs, in-progress runs are recomputed from scratch so finished runs drop out
+        self.runs_in_progress.clear();
+        self.run_in_progress_oldest_age_seconds.clear();
+        let now = now();
 
-        let Workspace(w) = workspaces else { return };
+        for run in r.results {
+            let enqueued = match run.stats {
+                RunStatsSnapshot(stats) => stats.enqueued_time,
+                _ => None
+            };
+            let since = match run.status {
+                RunStatus::QUEUED => enqueued,
+                _ => run.start_time.or(enqueued)
+            };
+
+            let label = RunLabel::new(
+                format!("{:?}", run.status),
+                run.mode,
+                CommonLabel::new(
+                    run.repository_origin,
+                    run.pipeline_name,
+                    self.tag_labels(run.tags.into_iter().map(|t| (t.key, t.value)))
+                )
+            );
+            self.runs_in_progress.get_or_create(&label).inc();
+
+            if let Some(t) = since {
+                let age = self.run_in_progress_oldest_age_seconds.get_or_create(&label);
+                age.set(age.get().max(now - t));
+            }
+        }
+    }
+
+    pub(super) fn set_workspace_metrics(&mut self, workspaces: DagitQueryWorkspaceOrError) {
+        use DagitQueryWorkspaceOrError::{PythonError, Workspace};
+        use DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntriesLocationOrLoadError::{
+            PythonError as LoadError, RepositoryLocation
+        };
+
+        let w = match workspaces {
+            Workspace(w) => w,
+            PythonError(e) => {
+                self.section_error("workspace", "PythonError", e.class_name.as_deref(), &e.message);
+                return;
+            }
+        };
         self.workspace_location_last_update_seconds.clear();
+        self.workspace_location_status.clear();
+        self.workspace_location_load_error.clear();
+        self.runs_by_instigation_total.clear();
+        self.instigation_last_tick_seconds.clear();
+        self.instigation_last_successful_tick_seconds.clear();
+        self.instigation_last_tick_failed.clear();
+        self.schedule_last_expected_tick_timestamp_seconds.clear();
+        self.schedule_last_actual_tick_timestamp_seconds.clear();
+        self.schedule_missed_ticks.clear();
+        self.sensor_running.clear();
+        self.sensor_next_tick_timestamp_seconds.clear();
+        self.sensor_min_interval_seconds.clear();
+        self.sensor_target_info.clear();
 
         for workspace in w.location_entries {
             self.workspace_location_last_update_seconds
                 .get_or_create(&WorkspaceLocationLabel::new(&workspace))
                 .set(workspace.updated_timestamp);
+            for state in [RepositoryLocationLoadStatus::LOADING, RepositoryLocationLoadStatus::LOADED] {
+                let is_current = workspace.load_status == state;
+                self.workspace_location_status
+                    .get_or_create(&WorkspaceLocationStateLabel::new(&workspace, &format!("{state:?}")))
+                    .set(i64::from(is_current));
+            }
 
-            let Some(RepositoryLocation(location)) = workspace.location_or_load_error else {
-                return;
+            // A location which failed to load (or is still loading) has nothing else to report, but mustn't keep
+            // the locations after it from being reported
+            let location = match workspace.location_or_load_error {
+                Some(RepositoryLocation(location)) => location,
+                Some(LoadError(ref e)) => {
+                    self.workspace_location_load_error
+                        .get_or_create(&WorkspaceLocationErrorLabel::new(&workspace, e.class_name.clone()))
+                        .set(1);
+                    self.section_error("workspace_location", "PythonError", e.class_name.as_deref(), &e.message);
+                    continue;
+                }
+                None => continue
             };
-            self.runs_by_instigation_total.clear();
 
             for repo in location.repositories {
                 for sensor in repo.sensors {
+                    let sensor_label = SensorLabel::new(workspace.name.clone(), repo.name.clone(), &sensor);
+                    let running = matches!(sensor.sensor_state.status, InstigationStatus::RUNNING);
+                    self.sensor_running.get_or_create(&sensor_label).set(i64::from(running));
+                    self.sensor_min_interval_seconds.get_or_create(&sensor_label).set(sensor.min_interval_seconds);
+                    if let Some(t) = sensor.next_tick.as_ref().and_then(|tick| tick.timestamp) {
+                        self.sensor_next_tick_timestamp_seconds.get_or_create(&sensor_label).set(t);
+                    }
+                    for target in sensor.targets.iter().flatten() {
+                        self.sensor_target_info
+                            .get_or_create(&sensor_label.target_label(target.pipeline_name.clone()))
+                            .set(1);
+                    }
+
                     let label = InstigationLabel::new(
                         workspace.name.clone(),
                         location.name.clone(),
@@ -90,21 +287,256 @@ impl Metrics {
                         format!("sensor_{:?}", sensor.sensor_type)
                     );
                     self.runs_by_instigation_total.get_or_create(&label).set(sensor.sensor_state.runs_count);
+                    self.set_tick_metrics(&label, &sensor.sensor_state);
                 }
 
-                for schedule in repo.schedules {
+                for schedule in &repo.schedules {
                     let label = InstigationLabel::new(
                         workspace.name.clone(),
                         location.name.clone(),
-                        schedule.name,
+                        schedule.name.clone(),
                         format!("schedule_{}", schedule.mode)
                     );
                     self.runs_by_instigation_total.get_or_create(&label).set(schedule.schedule_state.runs_count);
+                    self.set_tick_metrics(&label, &schedule.schedule_state);
+                    self.set_missed_tick_metrics(&label, schedule);
                 }
             }
         }
     }
 
+    pub(super) fn set_asset_metrics(&self, assets: Vec<AssetQueryAssetNodes>) {
+        self.asset_freshness_current_minutes_late.clear();
+        self.asset_freshness_latest_materialization_minutes_late.clear();
+        self.asset_freshness_maximum_lag_minutes.clear();
+        self.asset_stale_status.clear();
+
+        for asset in assets {
+            let label = AssetLabel::new(&asset.asset_key.path, asset.group_name);
+
+            if let Some(freshness) = asset.freshness_info {
+                if let Some(m) = freshness.current_minutes_late {
+                    self.asset_freshness_current_minutes_late.get_or_create(&label).set(m);
+                }
+                if let Some(m) = freshness.latest_materialization_minutes_late {
+                    self.asset_freshness_latest_materialization_minutes_late.get_or_create(&label).set(m);
+                }
+            }
+            if let Some(policy) = asset.freshness_policy {
+                self.asset_freshness_maximum_lag_minutes.get_or_create(&label).set(policy.maximum_lag_minutes);
+            }
+
+            // Exported as a state-set: every possible status is present and only the current one is set to 1
+            if let Some(current) = asset.stale_status.map(|s| format!("{s:?}")) {
+                for state in [StaleStatus::MISSING, StaleStatus::STALE, StaleStatus::FRESH].map(|s| format!("{s:?}")) {
+                    let is_current = state == current;
+                    self.asset_stale_status.get_or_create(&label.state_label(&state)).set(i64::from(is_current));
+                }
+            }
+        }
+    }
+
+    pub(super) fn set_partition_metrics(&self, assets: Vec<AssetPartitionQueryAssetNodes>) {
+        use AssetPartitionQueryAssetNodesAssetPartitionStatuses::TimePartitionStatuses;
+
+        self.asset_partitions.clear();
+        self.asset_latest_partition_end_timestamp_seconds.clear();
+
+        for asset in assets.into_iter().filter(|a| a.is_partitioned) {
+            let label = AssetLabel::new(&asset.asset_key.path, asset.group_name);
+
+            if let Some(stats) = asset.partition_stats {
+                // Partitions being re-materialized count as both materialized and materializing
+                let missing =
+                    (stats.num_partitions - stats.num_materialized - stats.num_failed - stats.num_materializing).max(0);
+                for (state, n) in [
+                    ("materialized", stats.num_materialized),
+                    ("failed", stats.num_failed),
+                    ("materializing", stats.num_materializing),
+                    ("missing", missing)
+                ] {
+                    self.asset_partitions.get_or_create(&label.state_label(state)).set(n);
+                }
+            }
+
+            // Only time-partitioned assets have an ordering to their partitions
+            if let TimePartitionStatuses(statuses) = asset.asset_partition_statuses {
+                let latest = statuses
+                    .ranges
+                    .iter()
+                    .filter(|r| matches!(r.status, PartitionRangeStatus::MATERIALIZED))
+                    .map(|r| r.end_time);
+                if let Some(end) = latest.reduce(f64::max) {
+                    self.asset_latest_partition_end_timestamp_seconds.get_or_create(&label).set(end);
+                }
+            }
+        }
+    }
+
+    pub(super) fn set_auto_materialize_metrics(&self, paused: bool) {
+        self.auto_materialize_paused.set(i64::from(paused));
+    }
+
+    pub(super) fn set_auto_materialize_evaluation_metrics(
+        &mut self, label: AssetLabel, evaluations: AutoMaterializeQueryAutoMaterializeAssetEvaluationsOrError
+    ) {
+        use AutoMaterializeQueryAutoMaterializeAssetEvaluationsOrError::{
+            AutoMaterializeAssetEvaluationNeedsMigrationError, AutoMaterializeAssetEvaluationRecords
+        };
+
+        let e = match evaluations {
+            AutoMaterializeAssetEvaluationRecords(e) => e,
+            AutoMaterializeAssetEvaluationNeedsMigrationError(e) => {
+                self.section_error(
+                    "auto_materialize_evaluations",
+                    "AutoMaterializeAssetEvaluationNeedsMigrationError",
+                    None,
+                    &e.message
+                );
+                return;
+            }
+        };
+
+        if let Some(t) = e.records.iter().map(|r| r.timestamp).reduce(f64::max) {
+            self.auto_materialize_last_evaluation_seconds.get_or_create(&label).set(t);
+        }
+
+        // Count each evaluation once, and only those made since the exporter was started
+        let cursor = self.auto_materialize_cursors.entry(label.clone()).or_insert(-1);
+        let since = *cursor;
+        for record in e.records.iter().filter(|r| r.evaluation_id > since && r.timestamp >= self.started) {
+            for (decision, n) in [
+                ("requested", record.num_requested),
+                ("skipped", record.num_skipped),
+                ("discarded", record.num_discarded)
+            ] {
+                self.auto_materialize_evaluations_total.get_or_create(&label.decision_label(decision)).inc_by(n as u64);
+            }
+            *cursor = (*cursor).max(record.evaluation_id);
+        }
+    }
+
+    pub(super) fn set_backfill_metrics(&mut self, backfills: BackfillQueryPartitionBackfillsOrError) {
+        use BackfillQueryPartitionBackfillsOrError::{PartitionBackfills, PythonError};
+
+        let b = match backfills {
+            PartitionBackfills(b) => b,
+            PythonError(e) => {
+                self.section_error("backfills", "PythonError", e.class_name.as_deref(), &e.message);
+                return;
+            }
+        };
+
+        self.backfill_partitions.clear();
+        self.backfill_cancelable_partitions.clear();
+        self.backfill_partition_runs.clear();
+        self.backfill_age_seconds.clear();
+        self.backfill_duration_seconds.clear();
+        let now = now();
+
+        // Only the most recent backfills are queried, so forget the ones which fell out of that window
+        self.backfills_seen.retain(|id| b.results.iter().any(|x| &x.id == id));
+
+        for backfill in b.results {
+            let terminal = matches!(
+                backfill.status,
+                BulkActionStatus::COMPLETED | BulkActionStatus::FAILED | BulkActionStatus::CANCELED
+            );
+            let label = BackfillLabel::new(&backfill);
+
+            // Backfills which finished before the exporter was started are not counted, like runs
+            let finished = backfill.end_timestamp.unwrap_or(backfill.timestamp);
+            if terminal && finished >= self.started && self.backfills_seen.insert(backfill.id.clone()) {
+                self.backfill_total.get_or_create(&label).inc();
+            }
+
+            let label = label.backfill_id_label(backfill.id);
+            if let Some(n) = backfill.num_partitions {
+                self.backfill_partitions.get_or_create(&label).set(n);
+            }
+            self.backfill_cancelable_partitions.get_or_create(&label).set(backfill.num_cancelable);
+            for counts in backfill.partition_status_counts {
+                self.backfill_partition_runs
+                    .get_or_create(&label.run_status_label(&counts.run_status))
+                    .set(counts.count);
+            }
+
+            self.backfill_age_seconds.get_or_create(&label).set(now - backfill.timestamp);
+            if let Some(end) = backfill.end_timestamp {
+                self.backfill_duration_seconds.get_or_create(&label).set(end - backfill.timestamp);
+            }
+        }
+    }
+
+    fn set_tick_metrics(&mut self, label: &InstigationLabel, state: &InstigatorState) {
+        use InstigationTickStatus::{FAILURE, STARTED};
+
+        // Ticks are returned newest-first. Count each finished tick once by advancing a per-instigator cursor
+        // over them, oldest-first, but never past a tick which is still in-flight so it is counted when it ends.
+        // A tick which has been in-flight for too long is never going to end, so it is skipped instead
+        let now = now();
+        let cursor = self.tick_cursors.entry(label.clone()).or_insert(self.started);
+        let since = *cursor;
+        for tick in state.ticks.iter().rev().filter(|t| t.timestamp > since) {
+            if matches!(tick.status, STARTED) {
+                if now - tick.timestamp < self.stuck_tick_timeout {
+                    break;
+                }
+                continue;
+            }
+            self.instigation_ticks_total.get_or_create(&label.tick_label(&tick.status)).inc();
+            *cursor = tick.timestamp;
+        }
+
+        if let Some(tick) = state.ticks.first() {
+            self.instigation_last_tick_seconds.get_or_create(label).set(tick.timestamp);
+        }
+        if let Some(tick) = state.last_successful_tick.first() {
+            self.instigation_last_successful_tick_seconds.get_or_create(label).set(tick.timestamp);
+        }
+        if let Some(tick) = state.ticks.iter().find(|t| !matches!(t.status, STARTED)) {
+            let error_class = tick.error.as_ref().and_then(|e| e.class_name.clone());
+            let failed = matches!(tick.status, FAILURE);
+            self.instigation_last_tick_failed.get_or_create(&label.error_label(error_class)).set(i64::from(failed));
+        }
+    }
+
+    fn set_missed_tick_metrics(
+        &mut self, label: &InstigationLabel,
+        schedule: &DagitQueryWorkspaceOrErrorOnWorkspaceLocationEntriesLocationOrLoadErrorOnRepositoryLocationRepositoriesSchedules
+    ) {
+        use InstigatorStateTypeSpecificData::ScheduleData;
+
+        let state = &schedule.schedule_state;
+        let Some(cron) = CronSchedule::parse(&schedule.cron_schedule, schedule.execution_timezone.as_deref()) else {
+            if self.unparsable_schedules.insert((label.clone(), schedule.cron_schedule.clone())) {
+                eprintln!("Cannot parse the cron schedule {:?} for schedule {}", schedule.cron_schedule, schedule.name);
+            }
+            return;
+        };
+        let now = now();
+
+        if let Some(t) = cron.last_tick(now) {
+            self.schedule_last_expected_tick_timestamp_seconds.get_or_create(label).set(t);
+        }
+        let last_actual = state.ticks.first().map(|t| t.timestamp);
+        if let Some(t) = last_actual {
+            self.schedule_last_actual_tick_timestamp_seconds.get_or_create(label).set(t);
+        }
+
+        // Ticks can only be missed since the schedule was last turned on, or since the exporter started
+        let started = match &state.type_specific_data {
+            Some(ScheduleData(data)) => data.start_timestamp,
+            _ => None
+        };
+        let since = [last_actual, started].into_iter().flatten().reduce(f64::max).unwrap_or(self.started);
+        let missed = match state.status {
+            InstigationStatus::RUNNING => cron.ticks_between(since, now - self.schedule_tick_grace),
+            _ => 0
+        };
+        self.schedule_missed_ticks.get_or_create(label).set(missed as i64);
+    }
+
     pub(super) fn set_daemon_metrics(&self, daemons: DagitQueryInstanceDaemonHealth) {
         self.daemon_last_heartbeat_seconds.clear();
         for daemon in daemons.all_daemon_statuses {
@@ -131,43 +563,157 @@ impl Metrics {
 
     /// Clear out label sets for outdated run states
     fn clear_old_run_states(&self, label: &RunLabel) {
-        let variants = [
-            RunStatus::QUEUED,
-            RunStatus::NOT_STARTED,
-            RunStatus::MANAGED,
-            RunStatus::STARTING,
-            RunStatus::STARTED,
-            RunStatus::SUCCESS,
-            RunStatus::FAILURE,
-            RunStatus::CANCELING,
-            RunStatus::CANCELED
-        ];
-        for status_variant in variants.map(|s| format!("{s:?}")) {
-            if label.status != status_variant {
-                let mut old_label = label.clone();
-                old_label.status = status_variant;
-                self.run_duration_seconds.remove(&old_label);
-                self.run_queue_seconds.remove(&old_label);
-            }
-        }
+        self.run_duration_seconds.remove_matching(|l| l.differs_by_status(label));
+        self.run_queue_seconds.remove_matching(|l| l.differs_by_status(label));
     }
 
     fn clear_old_step_states(&self, label: &StepLabel) {
-        let variants = [
-            Some(StepEventStatus::SKIPPED),
-            Some(StepEventStatus::SUCCESS),
-            Some(StepEventStatus::FAILURE),
-            Some(StepEventStatus::IN_PROGRESS),
-            None
-        ];
-
-        for status_variant in variants.map(|v| v.map(|inner| format!("{inner:?}"))) {
-            if label.status != status_variant {
-                let mut old_label = label.clone();
-                old_label.status = status_variant;
-                self.step_attempts.remove(&old_label);
-                self.step_duration_seconds.remove(&old_label);
-            }
-        }
+        self.step_attempts.remove_matching(|l| l.differs_by_status(label));
+        self.step_duration_seconds.remove_matching(|l| l.differs_by_status(label));
+    }
+
+    /// Remove the series of runs, steps, expectations and materializations which haven't been seen for longer than
+    /// the metric TTL, e.g. because their job or step was renamed or deleted
+    pub(super) fn clear_stale_metrics(&self) {
+        let Some(ttl) = self.metric_ttl else { return };
+        let since = now() - ttl;
+
+        self.run_total.remove_stale(since);
+        self.run_failures_total.remove_stale(since);
+        self.run_duration_seconds.remove_stale(since);
+        self.run_queue_seconds.remove_stale(since);
+        self.run_duration_histogram.remove_stale(since);
+        self.run_queue_histogram.remove_stale(since);
+        self.step_total.remove_stale(since);
+        self.step_duration_seconds.remove_stale(since);
+        self.step_duration_histogram.remove_stale(since);
+        self.step_worker_startup_histogram.remove_stale(since);
+        self.step_resource_init_histogram.remove_stale(since);
+        self.step_retry_delay_histogram.remove_stale(since);
+        self.step_attempts.remove_stale(since);
+        self.expectation_failure.remove_stale(since);
+        self.asset_materialization_timestamp.remove_stale(since);
+        self.asset_metadata.remove_stale(since);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    use serde_json::json;
+
+    fn runs_page(runs: &[(&str, f64)]) -> RunsQueryRunsOrError {
+        let results: Vec<_> = runs
+            .iter()
+            .map(|(run_id, update_time)| {
+                json!({
+                    "runId": run_id,
+                    "pipelineName": "job",
+                    "status": "SUCCESS",
+                    "mode": "default",
+                    "updateTime": update_time,
+                    "tags": [],
+                    "stats": {"__typename": "RunStatsSnapshot"},
+                    "stepStats": [],
+                    "assetMaterializations": []
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({"__typename": "Runs", "count": runs.len(), "results": results})).unwrap()
+    }
+
+    #[test]
+    fn run_pages() {
+        let mut m = Metrics::new("test", &Options::default());
+        let since = m.cursor;
+
+        // A full page leaves the cursor where it was, and carries on after the page's last run
+        assert!(m.set_run_metrics(runs_page(&[("a", since + 2.0), ("b", since + 1.0)]), 2));
+        assert_eq!(m.runs_page_cursor.as_deref(), Some("b"));
+        assert_eq!(m.cursor, since);
+
+        // A partial page is the last one, after which the cursor moves to the latest update seen on any page
+        assert!(!m.set_run_metrics(runs_page(&[("c", since + 1.5)]), 2));
+        assert_eq!(m.runs_page_cursor, None);
+        assert_eq!(m.cursor, since + 2.0);
+
+        // So is an empty page, which happens when the previous page was exactly full
+        assert!(m.set_run_metrics(runs_page(&[("d", since + 3.0), ("e", since + 4.0)]), 2));
+        assert!(!m.set_run_metrics(runs_page(&[]), 2));
+        assert_eq!(m.runs_page_cursor, None);
+        assert_eq!(m.cursor, since + 4.0);
+    }
+
+    /// An instigator's state with the given ticks, newest first, as their status and unix seconds
+    fn instigator_state(ticks: &[(&str, f64)]) -> InstigatorState {
+        let ticks: Vec<_> =
+            ticks.iter().map(|(status, time)| json!({"status": status, "timestamp": time, "error": null})).collect();
+        serde_json::from_value(json!({
+            "status": "RUNNING",
+            "runsCount": 0,
+            "ticks": ticks,
+            "lastSuccessfulTick": [],
+            "typeSpecificData": null
+        }))
+        .unwrap()
+    }
+
+    /// How many of the instigator's ticks were counted as succeeded and as failed
+    fn counted_ticks(m: &Metrics, label: &InstigationLabel) -> (u64, u64) {
+        use InstigationTickStatus::{FAILURE, SUCCESS};
+        let count = |status| m.instigation_ticks_total.get_or_create(&label.tick_label(&status)).get();
+        (count(SUCCESS), count(FAILURE))
+    }
+
+    fn tick_metrics() -> (Metrics, InstigationLabel, f64) {
+        let mut m = Metrics::new("test", &Options::default());
+        let t = now();
+        m.started = t - 86400.0;
+        let label = InstigationLabel::new("location".into(), "repo".into(), "sensor".into(), "SENSOR".into());
+        (m, label, t)
+    }
+
+    #[test]
+    fn ticks_counted_once() {
+        let (mut m, label, t) = tick_metrics();
+        m.set_tick_metrics(&label, &instigator_state(&[("SUCCESS", t - 10.0), ("FAILURE", t - 20.0)]));
+        assert_eq!(counted_ticks(&m, &label), (1, 1));
+
+        // The next refresh returns the same ticks again, along with a newer one
+        let ticks = [("SUCCESS", t - 5.0), ("SUCCESS", t - 10.0), ("FAILURE", t - 20.0)];
+        m.set_tick_metrics(&label, &instigator_state(&ticks));
+        assert_eq!(counted_ticks(&m, &label), (2, 1));
+    }
+
+    #[test]
+    fn ticks_wait_for_in_flight_tick() {
+        let (mut m, label, t) = tick_metrics();
+        let ticks = [("SUCCESS", t - 10.0), ("STARTED", t - 60.0), ("SUCCESS", t - 100.0)];
+        m.set_tick_metrics(&label, &instigator_state(&ticks));
+        assert_eq!(counted_ticks(&m, &label), (1, 0));
+
+        // Once the in-flight tick ends, it is counted along with the ticks after it
+        let ticks = [("SUCCESS", t - 10.0), ("FAILURE", t - 60.0), ("SUCCESS", t - 100.0)];
+        m.set_tick_metrics(&label, &instigator_state(&ticks));
+        assert_eq!(counted_ticks(&m, &label), (2, 1));
+    }
+
+    #[test]
+    fn ticks_skip_stuck_tick() {
+        let (mut m, label, t) = tick_metrics();
+        let ticks = [("SUCCESS", t - 10.0), ("STARTED", t - 4000.0), ("SUCCESS", t - 5000.0)];
+        m.set_tick_metrics(&label, &instigator_state(&ticks));
+        assert_eq!(counted_ticks(&m, &label), (2, 0));
+    }
+
+    #[test]
+    fn ticks_since_start() {
+        // Ticks from before the exporter started are left to the state it restores, if any
+        let (mut m, label, t) = tick_metrics();
+        m.started = t - 60.0;
+        m.set_tick_metrics(&label, &instigator_state(&[("SUCCESS", t - 10.0), ("SUCCESS", t - 100.0)]));
+        assert_eq!(counted_ticks(&m, &label), (1, 0));
     }
 }
@@ -1,27 +1,140 @@
 mod exporter;
 
 use exporter::Exporter;
+pub use exporter::Options;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use hyper::http::{Method, StatusCode};
 use hyper::rt::Executor;
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
 use hyper::{Body, Response};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::spawn_local;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, timeout_at, Duration, Instant};
 
 use std::cell::RefCell;
-use std::future::Future;
+use std::collections::HashSet;
+use std::future::{pending, Future};
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::rc::Rc;
 
-pub async fn serve(url: String, host: IpAddr, port: u16, refresh_secs: u64, concurrency_metrics: bool) -> Result<()> {
+/// A Dagster deployment to collect metrics from, whose name is the value of the instance label on its metrics
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    /// The url for the deployment's Dagit GraphQL API
+    pub url: String,
+    /// How many seconds to serve old metrics before re-querying the deployment, instead of the default refresh period
+    pub refresh: Option<u64>
+}
+
+pub async fn serve(targets: Vec<Target>, host: IpAddr, port: u16, refresh_secs: u64, options: Options) -> Result<()> {
+    let mut names = HashSet::new();
+    if let Some(t) = targets.iter().find(|t| !names.insert(&t.name)) {
+        return Err(anyhow!("the target name {} is used more than once", t.name));
+    }
+
     let addr: SocketAddr = (host, port).into();
     let listener = TcpListener::bind(&addr).await?;
     eprintln!("Listening on {addr}");
 
+    let mut exporters = Vec::with_capacity(targets.len());
+    for (i, target) in targets.into_iter().enumerate() {
+        let refresh_secs = target.refresh.unwrap_or(refresh_secs);
+        let mut options = options.clone();
+        // Each deployment's state is kept in a file of its own, named after the target. The first one keeps the file
+        // as given, so that adding deployments doesn't orphan the state of the one exported so far
+        if i > 0 {
+            options.state_file = options.state_file.map(|path| {
+                let mut path = path.into_os_string();
+                path.push(format!(".{}", target.name));
+                PathBuf::from(path)
+            });
+        }
+
+        let refresh =
+            if options.background_collection { Rc::new(RefCell::new(true)) } else { refresh_timer(refresh_secs) };
+        let partition_refresh = options.partition_refresh.map(refresh_timer);
+        let auto_materialize_refresh =
+            options.auto_materialize_metrics.then(|| refresh_timer(options.auto_materialize_refresh));
+
+        let exporter = Rc::new(Exporter::new(
+            target.name,
+            target.url,
+            Rc::clone(&refresh),
+            partition_refresh,
+            auto_materialize_refresh,
+            &options
+        ));
+        if options.background_collection {
+            collection_timer(Rc::clone(&exporter), refresh, refresh_secs);
+        }
+
+        if options.state_file.is_some() {
+            state_timer(Rc::clone(&exporter), options.state_interval);
+        }
+        exporters.push(exporter);
+    }
+    let exporters: Rc<[Rc<Exporter>]> = exporters.into();
+
+    let background_collection = options.background_collection;
+    let scrape_timeout = Duration::from_secs(options.scrape_timeout);
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            conn = listener.accept() => conn?.0,
+            () = &mut shutdown => break
+        };
+        let exporters = Rc::clone(&exporters);
+
+        spawn_local(async move {
+            if let Err(e) = metrics_handler(stream, exporters, background_collection, scrape_timeout).await {
+                eprintln!("Error serving connection: {e}");
+            }
+        });
+    }
+
+    for exporter in exporters.iter() {
+        if let Err(e) = exporter.save_state() {
+            eprintln!("Error saving state of {}: {e}", exporter.name());
+        }
+    }
+    Ok(())
+}
+
+/// Resolves once the process is asked to stop, by Ctrl-C or (on unix) SIGTERM. Signals which can't be listened for
+/// are left to stop the process the usual way
+async fn shutdown() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => s.recv().await.unwrap_or_default(),
+            Err(_) => pending().await
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    let interrupt = async {
+        if ctrl_c().await.is_err() {
+            pending().await
+        }
+    };
+
+    tokio::select! {
+        () = interrupt => {},
+        () = terminate => {}
+    }
+}
+
+/// Raise a flag every refresh period, telling the exporter to re-query Dagit on its next scrape
+fn refresh_timer(refresh_secs: u64) -> Rc<RefCell<bool>> {
     let mut timer = interval(Duration::from_secs(refresh_secs));
     let refresh = Rc::new(RefCell::new(true));
     let refresh_timer = Rc::clone(&refresh);
@@ -32,24 +145,43 @@ pub async fn serve(url: String, host: IpAddr, port: u16, refresh_secs: u64, conc
             *refresh.borrow_mut() = true;
         }
     });
+    refresh_timer
+}
 
-    let exporter = Rc::new(Exporter::new(url, refresh_timer, concurrency_metrics));
+/// Query Dagit every refresh period regardless of scrapes, which then only encode the last collected metrics
+fn collection_timer(exporter: Rc<Exporter>, refresh: Rc<RefCell<bool>>, refresh_secs: u64) {
+    let mut timer = interval(Duration::from_secs(refresh_secs));
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let exporter = Rc::clone(&exporter);
+    spawn_local(async move {
+        loop {
+            timer.tick().await;
+            *refresh.borrow_mut() = true;
+            if let Err(e) = exporter.query().await {
+                eprintln!("Error collecting metrics from {}: {e}", exporter.name());
+            }
+        }
+    });
+}
 
-        spawn_local(async move {
-            if let Err(e) = metrics_handler(stream, exporter).await {
-                eprintln!("Error serving connection: {e}");
+/// Save the exporter's state every interval, on top of saving it on shutdown
+fn state_timer(exporter: Rc<Exporter>, interval_secs: u64) {
+    let mut timer = interval(Duration::from_secs(interval_secs));
+
+    spawn_local(async move {
+        loop {
+            timer.tick().await;
+            if let Err(e) = exporter.save_state() {
+                eprintln!("Error saving state of {}: {e}", exporter.name());
             }
-        });
-    }
+        }
+    });
 }
 
 const OPENMETRICS_CONTENT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
-async fn metrics_handler(stream: TcpStream, exporter: Rc<exporter::Exporter>) -> Result<(), hyper::Error> {
+async fn metrics_handler(
+    stream: TcpStream, exporters: Rc<[Rc<Exporter>]>, background_collection: bool, scrape_timeout: Duration
+) -> Result<(), hyper::Error> {
     Http::new()
         .with_executor(LocalExec)
         .http1_only(true)
@@ -57,7 +189,7 @@ async fn metrics_handler(stream: TcpStream, exporter: Rc<exporter::Exporter>) ->
             stream,
             service_fn(|req| {
                 let resp = Response::builder();
-                let exporter = Rc::clone(&exporter);
+                let exporters = Rc::clone(&exporters);
                 async move {
                     if req.method() != Method::GET || req.uri() != "/metrics" {
                         return resp
@@ -65,11 +197,51 @@ async fn metrics_handler(stream: TcpStream, exporter: Rc<exporter::Exporter>) ->
                             .body(Body::from("only GET requests on the /metrics route are supported"));
                     }
 
-                    if let Err(e) = exporter.query().await {
-                        return resp.status(StatusCode::INTERNAL_SERVER_ERROR).body(e.to_string().into());
+                    // With background collection, scrapes only encode whatever was last collected. Otherwise, the
+                    // last collected metrics are still served if collecting fails, or takes longer than the scrape
+                    // timeout. A slow deployment's collection then carries on in the background, without holding up
+                    // the others
+                    if !background_collection {
+                        let deadline = Instant::now() + scrape_timeout;
+                        let queries: Vec<_> = exporters
+                            .iter()
+                            .map(|exporter| {
+                                let exporter = Rc::clone(exporter);
+                                spawn_local(async move {
+                                    if let Err(e) = exporter.query().await {
+                                        eprintln!("Error collecting metrics from {}: {e}", exporter.name());
+                                    }
+                                })
+                            })
+                            .collect();
+                        for (exporter, query) in exporters.iter().zip(queries) {
+                            if timeout_at(deadline, query).await.is_err() {
+                                let name = exporter.name();
+                                eprintln!("Serving the last collected metrics of {name}, whose collection is slow");
+                            }
+                        }
+                    }
+
+                    // A deployment whose metrics are too stale is left out, so that it doesn't hide the others. The
+                    // scrape only fails once there is nothing left to serve
+                    let mut errors = Vec::new();
+                    let fresh: Vec<_> = exporters
+                        .iter()
+                        .filter(|exporter| match exporter.check_staleness() {
+                            Ok(()) => true,
+                            Err(e) => {
+                                eprintln!("Leaving out the metrics of {}: {e}", exporter.name());
+                                errors.push(e.to_string());
+                                false
+                            }
+                        })
+                        .cloned()
+                        .collect();
+                    if fresh.is_empty() && !errors.is_empty() {
+                        return resp.status(StatusCode::INTERNAL_SERVER_ERROR).body(errors.join("\n").into());
                     }
 
-                    match exporter.encode() {
+                    match exporter::encode(&fresh) {
                         Ok(b) => resp.header("Content-Type", OPENMETRICS_CONTENT).body(b.into()),
                         Err(e) => resp.status(StatusCode::INTERNAL_SERVER_ERROR).body(e.to_string().into())
                     }
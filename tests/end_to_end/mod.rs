@@ -53,11 +53,14 @@ pub async fn end_to_end(img_name: &str, img_tag: &str) {
     // Start the dagster prometheus exporter
     eprintln!("Dagster docker container: {}", dagster.id());
     let _exporter = spawn_local(dagster_prom_exporter::serve(
-        dagit_url.clone(),
+        vec![dagster_prom_exporter::Target { name: "dagster".to_owned(), url: dagit_url.clone(), refresh: None }],
         IpAddr::V6(Ipv6Addr::LOCALHOST),
         3001,
         5,
-        false
+        dagster_prom_exporter::Options {
+            run_failure_metrics: true,
+            ..dagster_prom_exporter::Options::default()
+        }
     ));
     let exporter_url = "http://localhost:3001/metrics";
     let http = reqwest::Client::builder().pool_max_idle_per_host(0).build().unwrap();
@@ -86,6 +89,7 @@ pub async fn end_to_end(img_name: &str, img_tag: &str) {
     // Query exporter metrics for completed dagster pipelines
     let samples = parse_metrics(http.get(exporter_url)).await.expect("Can't parse prometheus metrics");
     let (asset_ts, scrape_ts) = test_completed_run_metrics(&samples);
+    test_run_failure_metrics(&samples);
 
     // Launch the same pipelines plus an additional one (ascii_job) which should
     // allow the alphabet asset materialization to succeed.
@@ -271,6 +275,33 @@ fn test_completed_run_metrics(samples: &[prometheus_parse::Sample]) -> (f64, f64
     }
 }
 
+fn test_run_failure_metrics(samples: &[prometheus_parse::Sample]) {
+    use prometheus_parse::Labels;
+    use prometheus_parse::Value::Untyped;
+
+    // The alphabet asset fails in its own step, so the failure is only counted
+    // for the step and not again for the run
+    let failures: Vec<&Labels> = samples
+        .iter()
+        .filter(|x| x.metric == "run_failures_total" && x.value == Untyped(1.0))
+        .map(|x| &x.labels)
+        .collect();
+
+    assert_eq!(
+        failures.len(),
+        1,
+        "Expected 1 sample for the run_failures_total metric, got: {failures:?}"
+    );
+    assert!(
+        failures.iter().all(|x| {
+            x.get("pipeline_name") == Some("__ASSET_JOB") &&
+                x.get("step_key") == Some("alphabet") &&
+                x.get("error_class").is_some()
+        }),
+        "The alphabet asset's step should fail with an error class but got: {failures:?}"
+    );
+}
+
 #[allow(clippy::too_many_lines)]
 fn test_more_run_metrics(samples: &[prometheus_parse::Sample], asset_ts: f64, scrape_ts: f64) {
     use prometheus_parse::Labels;